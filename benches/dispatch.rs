@@ -0,0 +1,76 @@
+/*!
+ * Counts how many instructions are dispatched running some BF programs at
+ * each optimization level, to show what superinstructions save.
+ *
+ * Run with `cargo bench`.
+ */
+
+extern crate stvm;
+
+use std::io;
+use std::time::Instant;
+
+use stvm::{Lang, OptLevel, Program, STVM};
+
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "hello",
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+    ),
+    (
+        "sierpinski",
+        "++++++++[>+>++++<<-]>++>>+<[-[>>+<<-]+>>]>+[-<<<[->[+[-]+>++>>>-<<]<[<]>>++++++[<<+++++>>-]+<<++.[-]<<]>.>+[>>]>+]",
+    ),
+    (
+        "walk",
+        "++++++++[>++++++++<-]>[[>]+[<]>>-<-]",
+    ),
+];
+
+const LEVELS: &[OptLevel] = &[OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3];
+
+// Run a program to the end, returning the number of dispatches and how long it took
+fn run(source: &str, opt_level: OptLevel, decoded: bool) -> (u64, f64) {
+    let mut program = Program::new(Lang::Bf, source);
+    program.compile(opt_level).unwrap();
+    let mut vm = STVM::from_program(program);
+    vm.set_io(io::empty(), io::sink());
+    if decoded {
+        vm.decode().unwrap();
+    }
+
+    let start = Instant::now();
+    vm.run().unwrap();
+    (vm.dispatch_count(), start.elapsed().as_secs_f64() * 1000.0)
+}
+
+fn main() {
+    println!(
+        "{:<12} {:>5} {:>12} {:>10} {:>10}",
+        "program", "level", "dispatches", "bytes ms", "decoded ms"
+    );
+    for &(name, source) in PROGRAMS {
+        let mut counts = vec![];
+        for &opt_level in LEVELS {
+            let (count, bytes_ms) = run(source, opt_level, false);
+            let (decoded_count, decoded_ms) = run(source, opt_level, true);
+            assert_eq!(count, decoded_count);
+            println!(
+                "{:<12} {:>5} {:>12} {:>10.3} {:>10.3}",
+                name,
+                format!("{:?}", opt_level),
+                count,
+                bytes_ms,
+                decoded_ms
+            );
+            counts.push(count);
+        }
+        let (o2, o3) = (counts[2], counts[3]);
+        println!(
+            "{:<12} superinstructions save {} dispatches ({:.1}%)\n",
+            name,
+            o2 - o3,
+            100.0 * (o2 - o3) as f64 / o2 as f64
+        );
+    }
+}
@@ -0,0 +1,541 @@
+/*!
+ * Assembler for STVM bytecode
+ *
+ * One instruction per line, written as the opcode's name followed by its
+ * operands if it has any:
+ *
+ * ```text
+ * ; comments start with a semicolon
+ *         Set 10
+ * loop:   Push
+ *         SubImmediate 1
+ *         JumpAbsoluteIfNonzero loop
+ *         HaltAlways
+ * ```
+ *
+ * Operands are decimal or `0x` hexadecimal numbers. Jumps may name a label
+ * instead, in which case relative jumps get the offset to the label.
+ * `.byte n` places a single raw byte, which is how the disassembler writes
+ * bytes that are not part of a valid instruction.
+ */
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use super::command::Opcode;
+use super::sourcemap::{SourceMap, SourcePosition, SourceSpan};
+use super::tape::Tape;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    MissingOperand(Opcode),
+    UnexpectedOperand(Opcode),
+    InvalidOperand(String),
+    OperandOutOfRange(i64),
+    // A label was used as the operand of an opcode which is not a jump
+    UnexpectedLabel(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+}
+
+/// An error in assembly source, and where it was found
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AsmErrorKind::*;
+        match self {
+            UnknownMnemonic(s) => write!(f, "Unknown mnemonic {:?}", s),
+            MissingOperand(op) => write!(f, "{:?} needs an operand", op),
+            UnexpectedOperand(op) => write!(f, "{:?} takes no operand", op),
+            InvalidOperand(s) => write!(f, "Invalid operand {:?}", s),
+            OperandOutOfRange(n) => write!(f, "Operand {} is out of range", n),
+            UnexpectedLabel(s) => write!(f, "Label {:?} used as operand of a non-jump", s),
+            UndefinedLabel(s) => write!(f, "Undefined label {:?}", s),
+            DuplicateLabel(s) => write!(f, "Label {:?} is defined more than once", s),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl Error for AsmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+enum Operand {
+    Number(i64),
+    Label(String),
+}
+
+// `None` for the `.byte` directive
+struct Instruction {
+    op: Option<Opcode>,
+    operands: Vec<Operand>,
+    offset: usize,
+    line: usize,
+    column: usize,
+    // column of the last character of the instruction
+    end_column: usize,
+}
+
+// Split a line into words along with their (1-based) columns, dropping comments
+fn words(line: &str) -> Vec<(usize, &str)> {
+    let line = match line.find(';') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                words.push((s, &line[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, &line[s..]));
+    }
+
+    words
+        .into_iter()
+        .map(|(i, w)| (line[..i].chars().count() + 1, w))
+        .collect()
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, digits) = if let Some(rest) = s.strip_prefix('-') {
+        (true, rest)
+    } else {
+        (false, s.strip_prefix('+').unwrap_or(s))
+    };
+    let n = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else {
+        digits.parse::<i64>().ok()?
+    };
+    Some(if negative { -n } else { n })
+}
+
+// Check that `n` fits in an operand of `op` which is `width` bytes, and return it as raw bits
+fn encode_operand(op: Opcode, width: usize, n: i64) -> Option<u32> {
+    let bits = 8 * width as u32;
+    let min = if op.has_signed_operand() {
+        -(1i64 << (bits - 1))
+    } else {
+        0
+    };
+    // unsigned spellings of signed numbers are accepted too
+    let max = (1i64 << bits) - 1;
+    if n < min || n > max {
+        None
+    } else {
+        Some((n as u64 & max as u64) as u32)
+    }
+}
+
+/// Turn assembly source into bytecode, along with the line each instruction came from
+pub fn assemble(source: &str) -> Result<(Tape<u8>, SourceMap), Vec<AsmError>> {
+    use self::AsmErrorKind::*;
+
+    let mut errors = vec![];
+    let mut labels = HashMap::new();
+    let mut instructions = vec![];
+
+    // First pass: find every instruction's offset and every label
+    let mut offset = 0;
+    for (line_index, line) in source.lines().enumerate() {
+        let line_number = line_index + 1;
+        let mut words = &words(line)[..];
+
+        while let Some(&(column, word)) = words.first() {
+            match word.strip_suffix(':') {
+                Some(name) if is_label(name) => {
+                    if labels.insert(name.to_string(), offset).is_some() {
+                        errors.push(AsmError {
+                            line: line_number,
+                            column,
+                            kind: DuplicateLabel(name.to_string()),
+                        });
+                    }
+                    words = &words[1..];
+                }
+                _ => break,
+            }
+        }
+
+        let (column, mnemonic) = match words.first() {
+            Some(&w) => w,
+            None => continue,
+        };
+
+        let op = match Opcode::from_name(mnemonic) {
+            Some(op) => Some(op),
+            None if mnemonic == ".byte" => None,
+            None => {
+                errors.push(AsmError {
+                    line: line_number,
+                    column,
+                    kind: UnknownMnemonic(mnemonic.to_string()),
+                });
+                continue;
+            }
+        };
+
+        let mut operands = vec![];
+        for &(column, word) in &words[1..] {
+            if let Some(n) = parse_number(word) {
+                operands.push(Operand::Number(n));
+            } else if is_label(word) {
+                operands.push(Operand::Label(word.to_string()));
+            } else {
+                errors.push(AsmError {
+                    line: line_number,
+                    column,
+                    kind: InvalidOperand(word.to_string()),
+                });
+            }
+        }
+        if operands.len() < words.len() - 1 {
+            continue;
+        }
+
+        let &(last_column, last_word) = words.last().unwrap();
+        instructions.push(Instruction {
+            op,
+            operands,
+            offset,
+            line: line_number,
+            column,
+            end_column: last_column + last_word.chars().count() - 1,
+        });
+        offset += op.map_or(1, |op| op.len());
+    }
+
+    // Second pass: encode, now that all labels are known
+    let mut bytecode = Tape::new(vec![]);
+    let mut source_map = SourceMap::new();
+    for instruction in instructions {
+        source_map.insert(
+            instruction.offset,
+            SourceSpan::new(
+                SourcePosition {
+                    line: instruction.line,
+                    column: instruction.column,
+                },
+                SourcePosition {
+                    line: instruction.line,
+                    column: instruction.end_column,
+                },
+            ),
+        );
+
+        let error = |kind| AsmError {
+            line: instruction.line,
+            column: instruction.column,
+            kind,
+        };
+
+        let op = match instruction.op {
+            Some(op) => op,
+            None => {
+                match instruction.operands.first() {
+                    _ if instruction.operands.len() > 1 => {
+                        errors.push(error(InvalidOperand(String::new())))
+                    }
+                    Some(&Operand::Number(n)) if (-128..=255).contains(&n) => {
+                        bytecode.push(n as u8)
+                    }
+                    Some(&Operand::Number(n)) => errors.push(error(OperandOutOfRange(n))),
+                    None => errors.push(error(InvalidOperand(String::new()))),
+                    Some(Operand::Label(name)) => errors.push(error(UnexpectedLabel(name.clone()))),
+                }
+                continue;
+            }
+        };
+
+        bytecode.push(op.into());
+        let widths = op.operand_widths();
+
+        if instruction.operands.len() > widths.len() {
+            errors.push(error(UnexpectedOperand(op)));
+        } else if instruction.operands.len() < widths.len() {
+            errors.push(error(MissingOperand(op)));
+        }
+
+        for (i, &width) in widths.iter().enumerate() {
+            let n = match instruction.operands.get(i) {
+                None => 0,
+                Some(&Operand::Number(n)) => n,
+                Some(Operand::Label(name))
+                    if i + 1 < widths.len() || !op.is_relative_jump() && !op.is_absolute_jump() =>
+                {
+                    errors.push(error(UnexpectedLabel(name.clone())));
+                    0
+                }
+                Some(Operand::Label(name)) => match labels.get(name) {
+                    Some(&target) if op.is_relative_jump() => {
+                        target as i64 - (instruction.offset + op.len()) as i64
+                    }
+                    Some(&target) => target as i64,
+                    None => {
+                        errors.push(error(UndefinedLabel(name.clone())));
+                        0
+                    }
+                },
+            };
+
+            let bits = match encode_operand(op, width, n) {
+                Some(bits) => bits,
+                None => {
+                    errors.push(error(OperandOutOfRange(n)));
+                    0
+                }
+            };
+            bytecode.push_int(width, bits);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok((bytecode, source_map))
+    } else {
+        errors.sort_by_key(|e| (e.line, e.column));
+        Err(errors)
+    }
+}
+
+// Format of the labels made up by the disassembler
+fn label_name(n: usize) -> String {
+    format!("L{}", n)
+}
+
+/// Turn bytecode into assembly which `assemble` turns back into the same bytes
+pub fn disassemble(bytecode: &Tape<u8>) -> String {
+    disassemble_annotated(bytecode, |_| None)
+}
+
+/// Like `disassemble`, but `comment` may give a comment for the instruction at each offset
+pub fn disassemble_annotated<F>(bytecode: &Tape<u8>, comment: F) -> String
+where
+    F: Fn(usize) -> Option<String>,
+{
+    use std::collections::{BTreeMap, HashSet};
+
+    // (offset, opcode, operands) or (offset, None, [raw byte])
+    let mut items = vec![];
+    let mut boundaries = HashSet::new();
+
+    let mut offset = 0;
+    while offset < bytecode.len() {
+        boundaries.insert(offset);
+        match Opcode::from_u8(bytecode[offset]) {
+            Some(op) if offset + op.len() <= bytecode.len() => {
+                let operands =
+                    op.decode_operands(&bytecode.as_slice()[offset + 1..offset + op.len()]);
+                items.push((offset, Some(op), operands));
+                offset += op.len();
+            }
+            _ => {
+                items.push((offset, None, vec![bytecode[offset] as i64]));
+                offset += 1;
+            }
+        }
+    }
+
+    let jump_target = |offset: usize, op: Opcode, n: i64| {
+        let target = if op.is_relative_jump() {
+            (offset + op.len()) as i64 + n
+        } else if op.is_absolute_jump() {
+            n
+        } else {
+            return None;
+        };
+        if target >= 0 && boundaries.contains(&(target as usize)) {
+            Some(target as usize)
+        } else {
+            None
+        }
+    };
+
+    let mut labels = BTreeMap::new();
+    for &(offset, op, ref operands) in items.iter() {
+        if let (Some(op), Some(&n)) = (op, operands.last()) {
+            if let Some(target) = jump_target(offset, op, n) {
+                labels.insert(target, String::new());
+            }
+        }
+    }
+    for (i, name) in labels.values_mut().enumerate() {
+        *name = label_name(i);
+    }
+
+    let mut output = String::new();
+    for (offset, op, operands) in items {
+        if let Some(name) = labels.get(&offset) {
+            output += &format!("{}:\n", name);
+        }
+        let line = match op {
+            None => format!(".byte {:#04x}", operands[0]),
+            Some(op) => {
+                let mut line = format!("{:?}", op);
+                let last = operands.len().saturating_sub(1);
+                for (i, n) in operands.into_iter().enumerate() {
+                    match jump_target(offset, op, n).filter(|_| i == last) {
+                        Some(target) => line += &format!(" {}", labels[&target]),
+                        None => line += &format!(" {}", n),
+                    }
+                }
+                line
+            }
+        };
+        match comment(offset) {
+            Some(comment) => output += &format!("    {:<40}; {}\n", line, comment),
+            None => output += &format!("    {}\n", line),
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::command::Opcode::*;
+    use super::super::prng::Prng;
+    use super::super::tape::Tape;
+    use super::AsmErrorKind::*;
+    use super::{assemble, disassemble, AsmError};
+
+    #[test]
+    fn assemble_test() {
+        let source = "
+            ; count down from 3
+                    Set 3
+            top:    SubImmediate 1      ; decrement
+                    MoveTapeShort -2
+                    MoveTapeLong 0x100
+                    JumpRelativeShortIfNonzero top
+                    JumpAbsoluteIfZero end
+            end:    HaltAlways
+        ";
+        let (bytecode, _) = assemble(source).unwrap();
+        assert_eq!(
+            bytecode.iter().cloned().collect::<Vec<u8>>(),
+            vec![
+                Set.into(),
+                3,
+                SubImmediate.into(),
+                1,
+                MoveTapeShort.into(),
+                0xfe,
+                MoveTapeLong.into(),
+                0x01,
+                0x00,
+                JumpRelativeShortIfNonzero.into(),
+                (-9i8) as u8,
+                JumpAbsoluteIfZero.into(),
+                0,
+                0,
+                0,
+                16,
+                HaltAlways.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn error_test() {
+        let source =
+            "Frobnicate\nSet\nSet 256\nNop 1\nSet lbl\nJumpAbsoluteIfZero nowhere\nx: Nop\nx: Nop";
+        let kinds: Vec<_> = assemble(source)
+            .unwrap_err()
+            .into_iter()
+            .map(|AsmError { line, kind, .. }| (line, kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, UnknownMnemonic("Frobnicate".to_string())),
+                (2, MissingOperand(Set)),
+                (3, OperandOutOfRange(256)),
+                (4, UnexpectedOperand(Nop)),
+                (5, UnexpectedLabel("lbl".to_string())),
+                (6, UndefinedLabel("nowhere".to_string())),
+                (8, DuplicateLabel("x".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn disassemble_test() {
+        let (bytecode, _) = assemble(
+            "
+            top:    MoveTapeShort -3
+                    SubImmediate 5
+                    MulSubRelativeLong -2 3
+                    JumpRelativeShortIfZero top
+                    JumpAbsoluteIfNonzero top
+                    MoveTapeShortJumpAbsoluteIfNonzero 0 top
+                    .byte 0xff
+                    JumpAbsoluteIfZero 1
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            disassemble(&bytecode),
+            "L0:
+    MoveTapeShort -3
+    SubImmediate 5
+    MulSubRelativeLong -2 3
+    JumpRelativeShortIfZero L0
+    JumpAbsoluteIfNonzero L0
+    MoveTapeShortJumpAbsoluteIfNonzero 0 L0
+    .byte 0xff
+    JumpAbsoluteIfZero 1
+"
+        );
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut prng = Prng::new_from_seed(1234);
+        for length in 0..200 {
+            let bytes: Vec<u8> = (0..length).map(|_| prng.gen_u8() % 40).collect();
+            let bytecode = Tape::new(bytes.clone());
+            let text = disassemble(&bytecode);
+            let (reassembled, _) = assemble(&text).unwrap();
+            assert_eq!(
+                reassembled.iter().cloned().collect::<Vec<u8>>(),
+                bytes,
+                "{}",
+                text
+            );
+        }
+    }
+}
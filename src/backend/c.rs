@@ -0,0 +1,183 @@
+/*!
+ * C backend
+ *
+ * Writes a standalone C file with the tape as a fixed size array and the
+ * cursor as a pointer into it. Unlike the VM, the generated code does not
+ * check for the cursor leaving the tape.
+ */
+
+use super::super::decode::{DecodedProgram, Instruction};
+use super::super::EofPolicy;
+use super::{structure, Item};
+
+const TAPE_SIZE: usize = 65536;
+const STACK_SIZE: usize = 65536;
+
+// Statement subtracting `n` from `target`, written as an addition when that is shorter
+fn subtract(target: &str, n: u8) -> String {
+    if n <= 128 {
+        format!("{} -= {};", target, n)
+    } else {
+        format!("{} += {};", target, n.wrapping_neg())
+    }
+}
+
+fn move_tape(n: isize) -> String {
+    if n < 0 {
+        format!("p -= {};", -n)
+    } else {
+        format!("p += {};", n)
+    }
+}
+
+fn read_byte(eof_policy: EofPolicy) -> &'static str {
+    match eof_policy {
+        EofPolicy::Unchanged => "if ((c = getchar()) != EOF) *p = c;",
+        EofPolicy::Zero => "*p = (c = getchar()) == EOF ? 0 : c;",
+        EofPolicy::MinusOne => "*p = (c = getchar()) == EOF ? 255 : c;",
+        EofPolicy::Error => {
+            "if ((c = getchar()) == EOF) { fputs(\"no bytes read from input\\n\", stderr); return 1; } *p = c;"
+        }
+    }
+}
+
+fn statement(instruction: Instruction, eof_policy: EofPolicy) -> String {
+    use super::super::decode::Instruction::*;
+
+    match instruction {
+        Nop => String::new(),
+        Sub(n) => subtract("*p", n),
+        SubRelative(offset) => format!("*p -= p[{}];", offset),
+        SubImmediateRelative(offset, n) => subtract(&format!("p[{}]", offset), n),
+        Set(n) => format!("*p = {};", n),
+        SetRelative(offset, n) => format!("p[{}] = {};", offset, n),
+        MulSubRelative(offset, factor) if factor <= 128 => {
+            format!("p[{}] -= *p * {};", offset, factor)
+        }
+        MulSubRelative(offset, factor) => {
+            format!("p[{}] += *p * {};", offset, factor.wrapping_neg())
+        }
+        MoveTape(n) => move_tape(n),
+        SubMoveTape(n, m) => format!("{} {}", subtract("*p", n), move_tape(m)),
+        MoveTapeJumpIfNonzero(n, target) => {
+            format!("{} if (*p) goto L{};", move_tape(n), target)
+        }
+        Seek(stride) => format!("while (*p) {}", move_tape(stride)),
+        JumpIfZero(target) => format!("if (!*p) goto L{};", target),
+        JumpIfNonzero(target) => format!("if (*p) goto L{};", target),
+        OutputByte => "putchar(*p);".to_string(),
+        InputByte => read_byte(eof_policy).to_string(),
+        HaltAlways => "return 0;".to_string(),
+        HaltIfNotEqual(n) => format!("if (*p != {}) return 0;", n),
+        Push => "stack[++sp] = *p;".to_string(),
+        Pop => "*p = stack[sp]; if (sp) sp--;".to_string(),
+        PushRand => "stack[++sp] = rand();".to_string(),
+    }
+}
+
+/// Write a decoded program as a C program, with input at its end handled like `eof_policy`
+pub fn emit(program: &DecodedProgram, eof_policy: EofPolicy) -> String {
+    use super::super::decode::Instruction::*;
+
+    let items = structure(program);
+    let uses = |f: &dyn Fn(Instruction) -> bool| {
+        items.iter().any(|item| match *item {
+            Item::Instruction(instruction, _) => f(instruction),
+            _ => false,
+        })
+    };
+    let uses_stack = uses(&|i| matches!(i, Push | Pop | PushRand));
+    let uses_input = uses(&|i| i == InputByte);
+
+    let mut output = String::new();
+    output += "#include <stdio.h>\n#include <stdlib.h>\n\n";
+    output += &format!("static unsigned char tape[{}];\n", TAPE_SIZE);
+    if uses_stack {
+        output += &format!("static unsigned char stack[{}];\n", STACK_SIZE);
+    }
+    output += "\nint main(void) {\n    unsigned char *p = tape;\n";
+    if uses_stack {
+        output += "    size_t sp = 0;\n";
+    }
+    if uses_input {
+        output += "    int c;\n";
+    }
+    output += "\n";
+
+    let mut depth = 1;
+    for item in items {
+        let line = match item {
+            Item::Label(target) => format!("L{}:;", target),
+            Item::Instruction(instruction, _) => statement(instruction, eof_policy),
+            Item::LoopStart => {
+                depth += 1;
+                output += &format!("{}while (*p) {{\n", "    ".repeat(depth - 1));
+                continue;
+            }
+            Item::LoopEnd => {
+                depth -= 1;
+                "}".to_string()
+            }
+        };
+        output += &format!("{}{}\n", "    ".repeat(depth), line);
+    }
+
+    // running off the end is an error in the VM
+    if program.instructions.last() != Some(&HaltAlways) {
+        output += "    fputs(\"unexpected end of program\\n\", stderr);\n    return 1;\n";
+    }
+    output += "}\n";
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use super::super::super::{EofPolicy, Lang, OptLevel, Program};
+
+    const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+    #[test]
+    fn emit_test() {
+        let mut program = Program::new(Lang::Bf, "+[->>+<[-]<]>>.,");
+        program.compile(OptLevel::O3).unwrap();
+        let c = program.to_c(EofPolicy::Zero).unwrap();
+        assert!(c.contains("static unsigned char tape[65536];"));
+        assert!(c.contains("    while (*p) {\n        *p -= 1;\n"));
+        assert!(c.contains("*p = (c = getchar()) == EOF ? 0 : c;"));
+        assert!(!c.contains("goto"));
+        assert!(!c.contains("stack"));
+    }
+
+    // Build the C for each optimization level with the system compiler, and check the binary
+    // prints what the VM does. Rust links with cc on Unix, so there it is always installed.
+    #[test]
+    #[cfg_attr(not(unix), ignore = "needs a C compiler called cc")]
+    fn native_test() {
+        let dir = std::env::temp_dir().join(format!("stvm-c-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for &opt_level in &[OptLevel::O0, OptLevel::O2, OptLevel::O3] {
+            let mut program = Program::new(Lang::Bf, HELLO);
+            program.compile(opt_level).unwrap();
+            let source: PathBuf = dir.join(format!("{:?}.c", opt_level));
+            let binary: PathBuf = dir.join(format!("{:?}", opt_level));
+            std::fs::write(&source, program.to_c(EofPolicy::Error).unwrap()).unwrap();
+
+            let built = Command::new("cc")
+                .arg("-o")
+                .arg(&binary)
+                .arg(&source)
+                .status()
+                .expect("building the C needs a C compiler called cc");
+            assert!(built.success());
+
+            let output = Command::new(&binary).output().unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"Hello World!\n");
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,130 @@
+/*!
+ * Translation of compiled bytecode into source code for other languages
+ *
+ * Backends work from the same decoded instructions the VM runs. Pairs of
+ * jumps which enclose a loop, as BF's brackets compile to, are recognized so
+ * they can be written as that language's loops. Any other jump is left as an
+ * instruction, with a label at its target.
+ */
+
+use std::collections::BTreeSet;
+
+use super::decode::{DecodedProgram, Instruction};
+
+pub mod c;
+pub mod rust;
+pub mod wat;
+
+/// A piece of a program laid out for a language with structured loops
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Item {
+    // A jump not turned into a loop lands on the instruction with this index
+    Label(usize),
+    // An instruction, along with the index it was at
+    Instruction(Instruction, usize),
+    // Loop while the current cell is nonzero, checking before each time round
+    LoopStart,
+    LoopEnd,
+}
+
+/// Lay out a decoded program as items, with the jumps making up loops replaced by `LoopStart`
+/// and `LoopEnd`
+pub fn structure(program: &DecodedProgram) -> Vec<Item> {
+    let instructions = &program.instructions;
+
+    // index of the jump closing the loop opened at each index
+    let mut closes = vec![None; instructions.len()];
+    // closing indices of the loops the current index is inside of
+    let mut open = vec![];
+    for (i, &instruction) in instructions.iter().enumerate() {
+        while open.last() == Some(&i) {
+            open.pop();
+        }
+        let close = match instruction {
+            Instruction::JumpIfZero(target) if target > i + 1 => target - 1,
+            _ => continue,
+        };
+        let jumps_back = match instructions[close] {
+            Instruction::JumpIfNonzero(back) | Instruction::MoveTapeJumpIfNonzero(_, back) => {
+                back == i + 1
+            }
+            _ => false,
+        };
+        // loops which would overlap the enclosing one are left as jumps
+        if jumps_back && open.last().is_none_or(|&outer| close < outer) {
+            closes[i] = Some(close);
+            open.push(close);
+        }
+    }
+    let is_close: BTreeSet<usize> = closes.iter().filter_map(|&close| close).collect();
+
+    let mut labels = BTreeSet::new();
+    for (i, &instruction) in instructions.iter().enumerate() {
+        if closes[i].is_some() || is_close.contains(&i) {
+            continue;
+        }
+        match instruction {
+            Instruction::JumpIfZero(target)
+            | Instruction::JumpIfNonzero(target)
+            | Instruction::MoveTapeJumpIfNonzero(_, target) => {
+                labels.insert(target);
+            }
+            _ => (),
+        }
+    }
+
+    let mut items = vec![];
+    for (i, &instruction) in instructions.iter().enumerate() {
+        if labels.contains(&i) {
+            items.push(Item::Label(i));
+        }
+        if closes[i].is_some() {
+            items.push(Item::LoopStart);
+        } else if is_close.contains(&i) {
+            if let Instruction::MoveTapeJumpIfNonzero(n, _) = instruction {
+                items.push(Item::Instruction(Instruction::MoveTape(n), i));
+            }
+            items.push(Item::LoopEnd);
+        } else if instruction != Instruction::Nop {
+            items.push(Item::Instruction(instruction, i));
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decode::{DecodedProgram, Instruction::*};
+    use super::{structure, Item};
+
+    #[test]
+    fn structure_test() {
+        let program = DecodedProgram {
+            instructions: vec![
+                Nop,
+                JumpIfZero(6),
+                JumpIfZero(5),
+                Sub(1),
+                JumpIfNonzero(3),
+                MoveTapeJumpIfNonzero(2, 2),
+                JumpIfNonzero(0),
+                HaltAlways,
+            ],
+            offsets: (0..8).collect(),
+        };
+        assert_eq!(
+            structure(&program),
+            vec![
+                Item::Label(0),
+                Item::LoopStart,
+                Item::LoopStart,
+                Item::Instruction(Sub(1), 3),
+                Item::LoopEnd,
+                Item::Instruction(MoveTape(2), 5),
+                Item::LoopEnd,
+                Item::Instruction(JumpIfNonzero(0), 6),
+                Item::Instruction(HaltAlways, 7),
+            ]
+        );
+    }
+}
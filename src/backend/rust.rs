@@ -0,0 +1,357 @@
+/*!
+ * Rust backend
+ *
+ * Writes a self-contained Rust module with a `run` function doing exactly
+ * what the VM does: the tape starts with one cell and grows to the right,
+ * cells wrap around, and moving left of the start is an error reporting the
+ * offset of the instruction, like `VmError::TapeError`.
+ *
+ * Bytecode with jumps that are not loops has no direct equivalent in Rust,
+ * so it is run by matching on the index of the next instruction instead.
+ */
+
+use super::super::decode::{DecodedProgram, Instruction};
+use super::super::EofPolicy;
+use super::{structure, Item};
+
+// Everything the generated `run` function needs apart from the program itself
+const PRELUDE: &str = "use std::io::{self, Read, Write};
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Error {
+    /// The cursor left the tape, at this bytecode offset
+    OutOfBounds(usize),
+    /// There was no input left to read
+    Eof,
+    /// Ran past the end of the bytecode
+    UnexpectedEof,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::OutOfBounds(offset) => write!(f, \"{:08x}: Out of Bounds\", offset),
+            Error::Eof => write!(f, \"no bytes read from input\"),
+            Error::UnexpectedEof => write!(f, \"Unexpected end of program\"),
+            Error::Io(e) => write!(f, \"I/O Error: {}\", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Index of the cell `offset` away from the cursor, growing the tape if it is past the end
+#[allow(dead_code)]
+fn cell(tape: &mut Vec<u8>, p: usize, offset: isize, location: usize) -> Result<usize, Error> {
+    let index = p as isize + offset;
+    if index < 0 {
+        return Err(Error::OutOfBounds(location));
+    }
+    let index = index as usize;
+    if index >= tape.len() {
+        tape.resize(index + 1, 0);
+    }
+    Ok(index)
+}
+
+// Read the cell `offset` away from the cursor, which has to be on the tape already
+#[allow(dead_code)]
+fn peek(tape: &[u8], p: usize, offset: isize, location: usize) -> Result<u8, Error> {
+    let index = p as isize + offset;
+    if index < 0 || index as usize >= tape.len() {
+        return Err(Error::OutOfBounds(location));
+    }
+    Ok(tape[index as usize])
+}
+
+#[allow(dead_code)]
+fn sub(tape: &mut Vec<u8>, p: usize, offset: isize, n: u8, location: usize) -> Result<(), Error> {
+    let i = cell(tape, p, offset, location)?;
+    tape[i] = tape[i].wrapping_sub(n);
+    Ok(())
+}
+
+// Move by `stride` at a time until on a zero cell
+#[allow(dead_code)]
+fn seek(tape: &mut Vec<u8>, mut p: usize, stride: isize, location: usize) -> Result<usize, Error> {
+    while tape[p] != 0 {
+        p = cell(tape, p, stride, location)?;
+    }
+    Ok(p)
+}
+
+#[allow(dead_code)]
+fn read<R: Read>(input: &mut R) -> Result<Option<u8>, Error> {
+    let mut buffer = [0u8; 1];
+    match input.read(&mut buffer).map_err(Error::Io)? {
+        0 => Ok(None),
+        _ => Ok(Some(buffer[0])),
+    }
+}
+
+#[allow(dead_code)]
+fn write<W: Write>(output: &mut W, byte: u8) -> Result<(), Error> {
+    output
+        .write_all(&[byte])
+        .and_then(|_| output.flush())
+        .map_err(Error::Io)
+}
+
+// The same generator `PushRand` uses in the VM
+#[allow(dead_code)]
+fn random(state: &mut u16) -> u8 {
+    let mut i = *state;
+    if i == 0x560a {
+        i = 0;
+    }
+    let mut s0 = (i << 8) ^ i;
+    i = s0.rotate_right(8);
+    s0 = i ^ ((s0 & 0xff) << 1);
+    let s1 = 0xff80 ^ (s0 >> 1);
+    *state = if s0 & 1 == 1 { 0x8180 } else { 0x1ff4 } ^ s1;
+    (*state & 0xff) as u8
+}
+
+#[allow(dead_code)]
+fn random_seed() -> u16 {
+    let mut seed = 0;
+    while seed == 0 || seed == 0x560a || seed == 0xe550 {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+        seed = (now.map(|d| d.subsec_nanos()).unwrap_or(1) % 0x10000) as u16;
+    }
+    seed
+}
+";
+
+fn input_byte(eof_policy: EofPolicy) -> &'static str {
+    match eof_policy {
+        EofPolicy::Unchanged => "if let Some(b) = read(input)? { tape[p] = b; }",
+        EofPolicy::Zero => "tape[p] = read(input)?.unwrap_or(0);",
+        EofPolicy::MinusOne => "tape[p] = read(input)?.unwrap_or(255);",
+        EofPolicy::Error => "tape[p] = read(input)?.ok_or(Error::Eof)?;",
+    }
+}
+
+// Rust for an instruction other than a jump, `location` being its bytecode offset
+fn statement(instruction: Instruction, location: usize, eof_policy: EofPolicy) -> String {
+    use super::super::decode::Instruction::*;
+
+    match instruction {
+        Nop => String::new(),
+        Sub(n) => format!("tape[p] = tape[p].wrapping_sub({});", n),
+        SubRelative(offset) => format!(
+            "tape[p] = tape[p].wrapping_sub(peek(&tape, p, {}, {})?);",
+            offset, location
+        ),
+        SubImmediateRelative(offset, n) => {
+            format!("sub(&mut tape, p, {}, {}, {})?;", offset, n, location)
+        }
+        Set(n) => format!("tape[p] = {};", n),
+        SetRelative(offset, n) => format!(
+            "let i = cell(&mut tape, p, {}, {})?; tape[i] = {};",
+            offset, location, n
+        ),
+        MulSubRelative(offset, factor) => format!(
+            "if tape[p] != 0 {{ let n = tape[p].wrapping_mul({}); sub(&mut tape, p, {}, n, {})?; }}",
+            factor, offset, location
+        ),
+        MoveTape(n) => format!("p = cell(&mut tape, p, {}, {})?;", n, location),
+        SubMoveTape(n, m) => format!(
+            "tape[p] = tape[p].wrapping_sub({}); p = cell(&mut tape, p, {}, {})?;",
+            n, m, location
+        ),
+        Seek(stride) => format!("p = seek(&mut tape, p, {}, {})?;", stride, location),
+        OutputByte => "write(output, tape[p])?;".to_string(),
+        InputByte => input_byte(eof_policy).to_string(),
+        HaltAlways => "return Ok(());".to_string(),
+        HaltIfNotEqual(n) => format!("if tape[p] != {} {{ return Ok(()); }}", n),
+        Push => "stack.push(tape[p]);".to_string(),
+        Pop => {
+            "tape[p] = if stack.len() > 1 { stack.pop().unwrap() } else { stack[0] };".to_string()
+        }
+        PushRand => "stack.push(random(&mut seed));".to_string(),
+        JumpIfZero(_) | JumpIfNonzero(_) | MoveTapeJumpIfNonzero(..) => {
+            unreachable!("jumps are written as loops or by `jump`")
+        }
+    }
+}
+
+// Rust for an instruction in a program run by instruction index
+fn jump(instruction: Instruction, location: usize, eof_policy: EofPolicy) -> String {
+    use super::super::decode::Instruction::*;
+
+    match instruction {
+        JumpIfZero(target) => format!("if tape[p] == 0 {{ pc = {}; continue; }}", target),
+        JumpIfNonzero(target) => format!("if tape[p] != 0 {{ pc = {}; continue; }}", target),
+        MoveTapeJumpIfNonzero(n, target) => format!(
+            "{} if tape[p] != 0 {{ pc = {}; continue; }}",
+            statement(MoveTape(n), location, eof_policy),
+            target
+        ),
+        _ => statement(instruction, location, eof_policy),
+    }
+}
+
+/// Write a decoded program as a Rust module, with input at its end handled like `eof_policy`
+pub fn emit(program: &DecodedProgram, eof_policy: EofPolicy) -> String {
+    use super::super::decode::Instruction::*;
+
+    let items = structure(program);
+    let uses = |f: &dyn Fn(Instruction) -> bool| program.instructions.iter().any(|&i| f(i));
+    let structured = !items.iter().any(|item| matches!(item, Item::Label(_)));
+
+    let mut output = String::new();
+    output += PRELUDE;
+    output += "\n/// Run the program until it halts\n";
+    output += "#[allow(unused_mut, unused_variables)]\n";
+    output +=
+        "pub fn run<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<(), Error> {\n";
+    output += "    let mut tape: Vec<u8> = vec![0];\n    let mut p: usize = 0;\n";
+    if uses(&|i| matches!(i, Push | Pop | PushRand)) {
+        output += "    let mut stack: Vec<u8> = vec![0];\n";
+    }
+    if uses(&|i| i == PushRand) {
+        output += "    let mut seed = random_seed();\n";
+    }
+    output += "\n";
+
+    if structured {
+        let mut depth = 1;
+        for item in items {
+            let line = match item {
+                Item::Instruction(instruction, index) => {
+                    statement(instruction, program.offsets[index], eof_policy)
+                }
+                Item::LoopStart => {
+                    depth += 1;
+                    output += &format!("{}while tape[p] != 0 {{\n", "    ".repeat(depth - 1));
+                    continue;
+                }
+                Item::LoopEnd => {
+                    depth -= 1;
+                    "}".to_string()
+                }
+                Item::Label(_) => unreachable!(),
+            };
+            output += &format!("{}{}\n", "    ".repeat(depth), line);
+        }
+        if program.instructions.last() != Some(&HaltAlways) {
+            output += "    Err(Error::UnexpectedEof)\n";
+        }
+    } else {
+        output += "    let mut pc = 0;\n    loop {\n        match pc {\n";
+        for (i, &instruction) in program.instructions.iter().enumerate() {
+            output += &format!(
+                "            {} => {{ {} }}\n",
+                i,
+                jump(instruction, program.offsets[i], eof_policy)
+            );
+        }
+        output += "            _ => return Err(Error::UnexpectedEof),\n        }\n";
+        output += "        pc += 1;\n    }\n";
+    }
+    output += "}\n";
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::super::super::{EofPolicy, Lang, OptLevel, Program};
+
+    // Build the generated module into a program printing what `run` returns, run it with
+    // `input` and return its stdout and stderr
+    fn build_and_run(program: &Program, name: &str, input: &[u8]) -> (Vec<u8>, String) {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let dir = std::env::temp_dir().join(format!("stvm-rust-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = dir.join(format!("{}_program.rs", name));
+        let main = dir.join(format!("{}.rs", name));
+        let binary = dir.join(name);
+        std::fs::write(&module, program.to_rust(EofPolicy::Zero).unwrap()).unwrap();
+        std::fs::write(
+            &main,
+            format!(
+                "mod program {{ include!({:?}); }}
+                fn main() {{
+                    let result = program::run(&mut std::io::stdin(), &mut std::io::stdout());
+                    eprint!(\"{{:?}}\", result);
+                }}",
+                module
+            ),
+        )
+        .unwrap();
+
+        let built = Command::new("rustc")
+            .arg("-o")
+            .arg(&binary)
+            .arg(&main)
+            .output()
+            .expect("building the generated Rust needs rustc");
+        assert!(
+            built.status.success(),
+            "{}",
+            String::from_utf8_lossy(&built.stderr)
+        );
+
+        let mut child = Command::new(&binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        (
+            output.stdout,
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        )
+    }
+
+    #[test]
+    fn native_test() {
+        // reverses its input, then walks off the left of the tape
+        let mut program = Program::new(Lang::Bf, ">,[>,]<[.<]<<[-]");
+        program.compile(OptLevel::O3).unwrap();
+        let rust = program.to_rust(EofPolicy::Zero).unwrap();
+        assert!(rust.contains("while tape[p] != 0 {"));
+        assert!(!rust.contains("pc"));
+
+        let (stdout, stderr) = build_and_run(&program, "reverse", b"stvm");
+        assert_eq!(stdout, b"mvts");
+
+        // the same error at the same place as the VM
+        let mut vm = super::super::super::STVM::from_program(program);
+        vm.set_io(&b"stvm"[..], std::io::sink());
+        vm.set_eof_policy(EofPolicy::Zero);
+        let offset = vm.run().unwrap_err().location().unwrap();
+        assert_eq!(stderr, format!("Err(OutOfBounds({}))", offset));
+    }
+
+    #[test]
+    fn unstructured_test() {
+        let mut program = Program::new(
+            Lang::Raw,
+            "
+                    Nop
+                    SubImmediate -3
+            top:    OutputByte
+                    Dec
+                    JumpAbsoluteIfNonzero top
+                    HaltAlways
+            ",
+        );
+        program.compile(OptLevel::O3).unwrap();
+        let rust = program.to_rust(EofPolicy::Zero).unwrap();
+        assert!(rust.contains("match pc {"));
+
+        let (stdout, stderr) = build_and_run(&program, "countdown", b"");
+        assert_eq!(stdout, [3, 2, 1]);
+        assert_eq!(stderr, "Ok(())");
+    }
+}
@@ -0,0 +1,388 @@
+/*!
+ * WebAssembly text backend
+ *
+ * Writes a WAT module exporting its memory and a `run` function. The first
+ * 64 KiB of memory hold the stack used by `Push` and `Pop`, and the tape
+ * starts after it, growing the memory as the tape grows. Input and output
+ * go through functions imported from `env`:
+ *
+ * ```text
+ * input_byte  () -> i32   the next byte of input, or -1 at the end of it
+ * output_byte (i32)       write out a byte
+ * random_byte () -> i32   only imported if the program uses PushRand
+ * ```
+ *
+ * Where the VM would return an error, such as for moving left of the start
+ * of the tape, the module traps.
+ */
+
+use super::super::decode::{DecodedProgram, Instruction};
+use super::super::EofPolicy;
+use super::{structure, Item};
+
+// Where the tape starts in memory, which is also the size of the stack
+const TAPE_BASE: usize = 65536;
+
+// Cell at the index which `index` evaluates to
+fn load(index: &str) -> String {
+    format!("(i32.load8_u offset={} {})", TAPE_BASE, index)
+}
+
+fn store(index: &str, value: &str) -> String {
+    format!("(i32.store8 offset={} {} {})", TAPE_BASE, index, value)
+}
+
+const CURSOR: &str = "(local.get $p)";
+
+// Index of the cell `offset` away from the cursor, growing the tape if needed
+fn cell(offset: isize) -> String {
+    format!(
+        "(call $cell (i32.add (local.get $p) (i32.const {})))",
+        offset
+    )
+}
+
+// Statements subtracting `n` from the cell `offset` away from the cursor
+fn subtract(offset: isize, n: &str) -> String {
+    if offset == 0 {
+        return store(CURSOR, &format!("(i32.sub {} {})", load(CURSOR), n));
+    }
+    format!(
+        "(local.set $i {}) {}",
+        cell(offset),
+        store(
+            "(local.get $i)",
+            &format!("(i32.sub {} {})", load("(local.get $i)"), n)
+        )
+    )
+}
+
+fn move_tape(n: isize) -> String {
+    format!("(local.set $p {})", cell(n))
+}
+
+fn input_byte(eof_policy: EofPolicy) -> String {
+    let byte = "(local.tee $i (call $input_byte))";
+    let at_end = "(i32.lt_s (local.get $i) (i32.const 0))";
+    match eof_policy {
+        EofPolicy::Unchanged => format!(
+            "(if (i32.ge_s {} (i32.const 0)) (then {}))",
+            byte,
+            store(CURSOR, "(local.get $i)")
+        ),
+        EofPolicy::Zero | EofPolicy::MinusOne => {
+            let eof = if eof_policy == EofPolicy::Zero {
+                0
+            } else {
+                255
+            };
+            format!(
+                "(drop {}) {}",
+                byte,
+                store(
+                    CURSOR,
+                    &format!("(select (i32.const {}) (local.get $i) {})", eof, at_end)
+                )
+            )
+        }
+        EofPolicy::Error => format!(
+            "(drop {}) (if {} (then unreachable)) {}",
+            byte,
+            at_end,
+            store(CURSOR, "(local.get $i)")
+        ),
+    }
+}
+
+// WAT for an instruction other than a jump
+fn statement(instruction: Instruction, eof_policy: EofPolicy) -> String {
+    use super::super::decode::Instruction::*;
+
+    let current = load(CURSOR);
+    match instruction {
+        Nop => "nop".to_string(),
+        Sub(n) => subtract(0, &format!("(i32.const {})", n)),
+        SubRelative(offset) => subtract(
+            0,
+            &format!(
+                "(call $peek (i32.add (local.get $p) (i32.const {})))",
+                offset
+            ),
+        ),
+        SubImmediateRelative(offset, n) => subtract(offset, &format!("(i32.const {})", n)),
+        Set(n) => store(CURSOR, &format!("(i32.const {})", n)),
+        SetRelative(offset, n) => format!(
+            "(local.set $i {}) {}",
+            cell(offset),
+            store("(local.get $i)", &format!("(i32.const {})", n))
+        ),
+        MulSubRelative(offset, factor) => format!(
+            "(if {} (then {}))",
+            current,
+            subtract(
+                offset,
+                &format!("(i32.mul {} (i32.const {}))", current, factor)
+            )
+        ),
+        MoveTape(n) => move_tape(n),
+        SubMoveTape(n, m) => format!(
+            "{} {}",
+            subtract(0, &format!("(i32.const {})", n)),
+            move_tape(m)
+        ),
+        Seek(stride) => format!(
+            "(block (loop (br_if 1 (i32.eqz {})) {} (br 0)))",
+            current,
+            move_tape(stride)
+        ),
+        OutputByte => format!("(call $output_byte {})", current),
+        InputByte => input_byte(eof_policy),
+        HaltAlways => "(return)".to_string(),
+        HaltIfNotEqual(n) => format!(
+            "(if (i32.ne {} (i32.const {})) (then (return)))",
+            current, n
+        ),
+        Push => format!("(call $push {})", current),
+        Pop => store(CURSOR, "(call $pop)"),
+        PushRand => "(call $push (call $random_byte))".to_string(),
+        JumpIfZero(_) | JumpIfNonzero(_) | MoveTapeJumpIfNonzero(..) => {
+            unreachable!("jumps are written as loops or by `jump`")
+        }
+    }
+}
+
+// WAT for an instruction in a program run by instruction index, ending by going to the next one
+fn jump(instruction: Instruction, index: usize, eof_policy: EofPolicy) -> String {
+    use super::super::decode::Instruction::*;
+
+    let next = format!("(local.set $pc (i32.const {}))", index + 1);
+    let go_to = |condition: &str, target: usize| {
+        format!(
+            "{} (if {} (then (local.set $pc (i32.const {}))))",
+            next, condition, target
+        )
+    };
+    let current = load(CURSOR);
+    match instruction {
+        JumpIfZero(target) => go_to(&format!("(i32.eqz {})", current), target),
+        JumpIfNonzero(target) => go_to(&current, target),
+        MoveTapeJumpIfNonzero(n, target) => format!("{} {}", move_tape(n), go_to(&current, target)),
+        _ => format!("{} {}", statement(instruction, eof_policy), next),
+    }
+}
+
+// Functions for getting at the tape and stack, which every module has
+const HELPERS: &str = "  (global $len (mut i32) (i32.const 1))
+  (global $sp (mut i32) (i32.const 0))
+
+  ;; index of a cell, growing the tape if it is past the end
+  (func $cell (param $i i32) (result i32)
+    (if (i32.lt_s (local.get $i) (i32.const 0)) (then unreachable))
+    (if (i32.ge_u (local.get $i) (global.get $len))
+      (then
+        (global.set $len (i32.add (local.get $i) (i32.const 1)))
+        (if (i32.gt_u
+              (i32.add (global.get $len) (i32.const 65536))
+              (i32.mul (memory.size) (i32.const 65536)))
+          (then
+            (if (i32.eq
+                  (memory.grow (i32.sub
+                    (i32.div_u (i32.add (global.get $len) (i32.const 131071)) (i32.const 65536))
+                    (memory.size)))
+                  (i32.const -1))
+              (then unreachable))))))
+    (local.get $i))
+
+  ;; a cell which has to be on the tape already
+  (func $peek (param $i i32) (result i32)
+    (if (i32.or
+          (i32.lt_s (local.get $i) (i32.const 0))
+          (i32.ge_s (local.get $i) (global.get $len)))
+      (then unreachable))
+    (i32.load8_u offset=65536 (local.get $i)))
+
+  (func $push (param $n i32)
+    (global.set $sp (i32.add (global.get $sp) (i32.const 1)))
+    (if (i32.ge_u (global.get $sp) (i32.const 65536)) (then unreachable))
+    (i32.store8 (global.get $sp) (local.get $n)))
+
+  ;; the bottom of the stack is always there, and is 0
+  (func $pop (result i32)
+    (i32.load8_u (global.get $sp))
+    (if (global.get $sp)
+      (then (global.set $sp (i32.sub (global.get $sp) (i32.const 1))))))
+";
+
+/// Write a decoded program as a WAT module, with input at its end handled like `eof_policy`
+pub fn emit(program: &DecodedProgram, eof_policy: EofPolicy) -> String {
+    use super::super::decode::Instruction::*;
+
+    let items = structure(program);
+    let structured = !items.iter().any(|item| matches!(item, Item::Label(_)));
+
+    let mut output = String::new();
+    output += "(module\n";
+    output += "  (import \"env\" \"input_byte\" (func $input_byte (result i32)))\n";
+    output += "  (import \"env\" \"output_byte\" (func $output_byte (param i32)))\n";
+    if program.instructions.contains(&PushRand) {
+        output += "  (import \"env\" \"random_byte\" (func $random_byte (result i32)))\n";
+    }
+    output += "  (memory (export \"memory\") 2)\n";
+    output += HELPERS;
+    output += "\n  (func (export \"run\")\n";
+    output += "    (local $p i32) (local $i i32) (local $pc i32)\n";
+
+    if structured {
+        let mut depth = 2;
+        for item in items {
+            let line = match item {
+                Item::Instruction(instruction, _) => statement(instruction, eof_policy),
+                Item::LoopStart => {
+                    depth += 1;
+                    format!("(block (loop (br_if 1 (i32.eqz {}))", load(CURSOR))
+                }
+                Item::LoopEnd => {
+                    depth -= 1;
+                    "(br 0)))".to_string()
+                }
+                Item::Label(_) => unreachable!(),
+            };
+            let indent = match item {
+                Item::LoopStart => depth - 1,
+                _ => depth,
+            };
+            output += &format!("{}{}\n", "  ".repeat(indent), line);
+        }
+        if program.instructions.last() != Some(&HaltAlways) {
+            output += "    unreachable\n";
+        }
+    } else {
+        output += "    (loop $next\n";
+        for (i, &instruction) in program.instructions.iter().enumerate() {
+            output += &format!(
+                "      (if (i32.eq (local.get $pc) (i32.const {})) (then {} (br $next)))\n",
+                i,
+                jump(instruction, i, eof_policy)
+            );
+        }
+        output += "    )\n    unreachable\n";
+    }
+    output += "  )\n)\n";
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate wasmparser;
+    extern crate wat;
+
+    use std::process::Command;
+
+    use super::super::super::{EofPolicy, Lang, OptLevel, Program};
+
+    // Node script running a module with its input as the first argument, printing its output
+    // and whether it trapped
+    const HARNESS: &str = "
+        const fs = require('fs');
+        const input = Buffer.from(process.argv[3]);
+        let position = 0;
+        const output = [];
+        const env = {
+            input_byte: () => (position < input.length ? input[position++] : -1),
+            output_byte: (b) => output.push(b),
+            random_byte: () => 4,
+        };
+        WebAssembly.instantiate(fs.readFileSync(process.argv[2]), { env }).then(({ instance }) => {
+            let result = 'ok';
+            try {
+                instance.exports.run();
+            } catch (e) {
+                result = 'trap';
+            }
+            process.stdout.write(Buffer.concat([Buffer.from(output), Buffer.from('\\n' + result)]));
+        });
+    ";
+
+    // Assemble a module and run it with node
+    // Turn the text into a binary module, checking that it is valid
+    fn assemble(wat: &str) -> Vec<u8> {
+        let module = wat::parse_str(wat).unwrap();
+        wasmparser::Validator::new().validate_all(&module).unwrap();
+        module
+    }
+
+    fn run(wat: &str, name: &str, input: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("stvm-wat-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = dir.join(format!("{}.wasm", name));
+        let harness = dir.join("harness.js");
+        std::fs::write(&module, assemble(wat)).unwrap();
+        std::fs::write(&harness, HARNESS).unwrap();
+
+        let output = Command::new("node")
+            .arg(&harness)
+            .arg(&module)
+            .arg(input)
+            .output()
+            .expect("running the module needs node");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    fn reverse() -> String {
+        let mut program = Program::new(Lang::Bf, ">,[>,]<[.<]<<[-]");
+        program.compile(OptLevel::O3).unwrap();
+        program.to_wat(EofPolicy::Zero).unwrap()
+    }
+
+    // counts down from a random byte, which the harness always makes 4
+    fn countdown() -> String {
+        let mut program = Program::new(
+            Lang::Raw,
+            "
+                    PushRand
+                    Pop
+            top:    OutputByte
+                    Dec
+                    JumpAbsoluteIfNonzero top
+                    HaltAlways
+            ",
+        );
+        program.compile(OptLevel::O3).unwrap();
+        program.to_wat(EofPolicy::Zero).unwrap()
+    }
+
+    #[test]
+    fn emit_test() {
+        let wat = reverse();
+        assert!(wat.starts_with("(module\n"));
+        assert!(wat.contains("(import \"env\" \"input_byte\" (func $input_byte (result i32)))"));
+        assert!(wat.contains("(block (loop (br_if 1 (i32.eqz"));
+        assert!(!wat.contains("random_byte"));
+        assert!(!wat.contains("$next"));
+
+        assert!(countdown().contains("(loop $next"));
+    }
+
+    #[test]
+    fn valid_test() {
+        for &opt_level in &[OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let mut program = Program::new(Lang::Bf, ">,[>,]<[.<]<<[-]+[->>+<<]>[-]");
+            program.compile(opt_level).unwrap();
+            for &policy in &[EofPolicy::Unchanged, EofPolicy::Zero, EofPolicy::Error] {
+                assemble(&program.to_wat(policy).unwrap());
+            }
+        }
+        assemble(&countdown());
+    }
+
+    // Run with `cargo test -- --ignored` where node is installed
+    #[test]
+    #[ignore = "needs node"]
+    fn run_test() {
+        assert_eq!(run(&reverse(), "reverse", "stvm"), "mvts\ntrap");
+        assert_eq!(
+            run(&countdown(), "countdown", ""),
+            "\u{4}\u{3}\u{2}\u{1}\nok"
+        );
+    }
+}
@@ -1,30 +1,699 @@
-use super::command;
+/*!
+ * BF front end
+ *
+ * Source is parsed into a tree of `Node`s, one per command with loops
+ * holding their bodies. Optimization passes each turn a tree into a new
+ * one, and `emit` writes the result out as bytecode. Every node keeps the
+ * span of source it came from, which ends up in the source map.
+ */
 
-enum IR1 {
-    Add(isize),
+use std::fmt;
+
+use super::command::Opcode;
+use super::sourcemap::{SourceMap, SourcePosition, SourceSpan};
+use super::tape::Tape;
+use super::OptLevel;
+
+/// A BF bracket without a partner, and where it is in the source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BracketError {
+    // `]` with no `[` before it
+    Unopened(SourcePosition),
+    // `[` which is never closed
+    Unclosed(SourcePosition),
+}
+
+impl BracketError {
+    pub fn position(&self) -> SourcePosition {
+        match *self {
+            BracketError::Unopened(position) | BracketError::Unclosed(position) => position,
+        }
+    }
+}
+
+impl fmt::Display for BracketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BracketError::Unopened(position) => write!(f, "{}: Unmatched ']'", position),
+            BracketError::Unclosed(position) => write!(f, "{}: Unclosed '['", position),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IR1 {
+    // Add to the cell `offset` away from the cursor
+    Add { offset: isize, n: u8 },
+    // Set the cell `offset` away from the cursor
+    Set { offset: isize, n: u8 },
     Move(isize),
     OutputByte,
     InputByte,
-    StartLoop,
-    EndLoop,
-}
-
-pub fn compile(sourcecode) -> Vec<i8> {
-    let v = vec![]; 
-    for c in self.sourcecode.chars() {
-        v.push(match c {
-            '+' => Add(1),
-            '-' => Add(-1),
-            '>' => Move(1),
-            '<' => Move(-1),
-            '.' => OutputByte,
-            ',' => InputByte,
-            '[' => StartLoop,
-            ']' => EndLoop,
+    Loop(Vec<Node>),
+    // Move `stride` cells at a time, left if negative, until on a zero cell
+    Seek(isize),
+    // Add the current cell times `factor` to the cell `offset` away
+    MulAdd { offset: isize, factor: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub ir: IR1,
+    pub span: SourceSpan,
+}
+
+impl Node {
+    fn new(ir: IR1, span: SourceSpan) -> Node {
+        Node { ir, span }
+    }
+}
+
+// Offsets have to fit in the operands of the `*RelativeLong` opcodes
+const MAX_OFFSET: isize = i16::MAX as isize;
+
+/// Turn source into a tree of single commands, or find every unmatched bracket
+pub fn parse(sourcecode: &str) -> Result<Vec<Node>, Vec<BracketError>> {
+    use self::IR1::*;
+
+    // the bodies of the loops currently open, and where they started
+    let mut stack: Vec<(Vec<Node>, SourcePosition)> = vec![];
+    let mut nodes = vec![];
+    let mut errors = vec![];
+
+    let mut position = SourcePosition { line: 1, column: 1 };
+    for c in sourcecode.chars() {
+        let span = SourceSpan::new(position, position);
+        let ir = match c {
+            '+' => Some(Add { offset: 0, n: 1 }),
+            '-' => Some(Add { offset: 0, n: 255 }),
+            '>' => Some(Move(1)),
+            '<' => Some(Move(-1)),
+            '.' => Some(OutputByte),
+            ',' => Some(InputByte),
+            '[' => {
+                stack.push((std::mem::take(&mut nodes), position));
+                None
+            }
+            ']' => {
+                match stack.pop() {
+                    Some((outer, start)) => {
+                        let body = std::mem::replace(&mut nodes, outer);
+                        nodes.push(Node::new(Loop(body), SourceSpan::new(start, position)));
+                    }
+                    None => errors.push(BracketError::Unopened(position)),
+                }
+                None
+            }
+            _ => None,
+        };
+        if let Some(ir) = ir {
+            nodes.push(Node::new(ir, span));
+        }
+
+        if c == '\n' {
+            position.line += 1;
+            position.column = 1;
+        } else {
+            position.column += 1;
+        }
+    }
+
+    errors.extend(
+        stack
+            .into_iter()
+            .map(|(_, start)| BracketError::Unclosed(start)),
+    );
+    if errors.is_empty() {
+        Ok(nodes)
+    } else {
+        errors.sort_by_key(|e| {
+            let position = e.position();
+            (position.line, position.column)
         });
+        Err(errors)
+    }
+}
+
+// Apply `pass` to every list of nodes in the tree, innermost loops first
+fn map_bodies<F>(nodes: Vec<Node>, pass: &F) -> Vec<Node>
+where
+    F: Fn(Vec<Node>) -> Vec<Node>,
+{
+    let nodes = nodes
+        .into_iter()
+        .map(|node| match node.ir {
+            IR1::Loop(body) => Node::new(IR1::Loop(map_bodies(body, pass)), node.span),
+            _ => node,
+        })
+        .collect();
+    pass(nodes)
+}
+
+/// Runs of adds to the current cell and runs of moves become a single add or move. Only moves in
+/// the same direction are merged, so that moving off the left of the tape and back still fails.
+pub fn merge_runs(nodes: Vec<Node>) -> Vec<Node> {
+    map_bodies(nodes, &|nodes| {
+        let mut output: Vec<Node> = vec![];
+        for node in nodes {
+            let merged = match (output.last().map(|last| &last.ir), &node.ir) {
+                (Some(&IR1::Add { offset: 0, n: a }), &IR1::Add { offset: 0, n: b }) => {
+                    Some(IR1::Add {
+                        offset: 0,
+                        n: a.wrapping_add(b),
+                    })
+                }
+                (Some(&IR1::Move(a)), &IR1::Move(b))
+                    if a.signum() == b.signum() && (a + b).abs() <= MAX_OFFSET =>
+                {
+                    Some(IR1::Move(a + b))
+                }
+                _ => None,
+            };
+            match merged {
+                Some(ir) => {
+                    let last = output.last_mut().unwrap();
+                    last.ir = ir;
+                    last.span.end = node.span.end;
+                }
+                None => output.push(node),
+            }
+        }
+        output
+            .into_iter()
+            .filter(|node| node.ir != IR1::Add { offset: 0, n: 0 } && node.ir != IR1::Move(0))
+            .collect()
+    })
+}
+
+/// `[-]` and `[+]` become `Set 0`
+pub fn clear_loops(nodes: Vec<Node>) -> Vec<Node> {
+    map_bodies(nodes, &|nodes| {
+        nodes
+            .into_iter()
+            .map(|node| match node.ir {
+                IR1::Loop(ref body) if is_clear_body(body) => {
+                    Node::new(IR1::Set { offset: 0, n: 0 }, node.span)
+                }
+                _ => node,
+            })
+            .collect()
+    })
+}
+
+fn is_clear_body(body: &[Node]) -> bool {
+    match body {
+        [Node {
+            ir: IR1::Add { offset: 0, n },
+            ..
+        }] => *n == 1 || *n == 255,
+        _ => false,
+    }
+}
+
+/// `[>]`, `[<<]` and other loops which only move one way become seeks
+pub fn scan_loops(nodes: Vec<Node>) -> Vec<Node> {
+    map_bodies(nodes, &|nodes| {
+        nodes
+            .into_iter()
+            .map(|node| {
+                let stride = match node.ir {
+                    IR1::Loop(ref body) => scan_stride(body),
+                    _ => None,
+                };
+                match stride {
+                    Some(stride) => Node::new(IR1::Seek(stride), node.span),
+                    None => node,
+                }
+            })
+            .collect()
+    })
+}
+
+fn scan_stride(body: &[Node]) -> Option<isize> {
+    let mut stride = 0isize;
+    for node in body {
+        match node.ir {
+            IR1::Move(n) if stride == 0 || n.signum() == stride.signum() => stride += n,
+            _ => return None,
+        }
+    }
+    if stride != 0 && stride.abs() <= u8::MAX as isize {
+        Some(stride)
+    } else {
+        None
+    }
+}
+
+/// Loops which count their current cell down or up by one while adding to other cells become
+/// multiply-adds followed by clearing the counter
+pub fn multiply_loops(nodes: Vec<Node>) -> Vec<Node> {
+    map_bodies(nodes, &|nodes| {
+        let mut output = vec![];
+        for node in nodes {
+            let cells = match node.ir {
+                IR1::Loop(ref body) => multiply_cells(body),
+                _ => None,
+            };
+            match cells {
+                Some(cells) => {
+                    for (offset, factor) in cells {
+                        output.push(Node::new(IR1::MulAdd { offset, factor }, node.span));
+                    }
+                    output.push(Node::new(IR1::Set { offset: 0, n: 0 }, node.span));
+                }
+                None => output.push(node),
+            }
+        }
+        output
+    })
+}
+
+fn multiply_cells(body: &[Node]) -> Option<Vec<(isize, u8)>> {
+    let mut offset = 0isize;
+    // the furthest left the body reaches
+    let mut lowest = 0isize;
+    // (offset, amount added) in the order the cells are first changed
+    let mut cells: Vec<(isize, u8)> = vec![];
+    for node in body {
+        match node.ir {
+            IR1::Add { offset: 0, n } => match cells.iter_mut().find(|&&mut (o, _)| o == offset) {
+                Some(cell) => cell.1 = cell.1.wrapping_add(n),
+                None => cells.push((offset, n)),
+            },
+            IR1::Move(n) if (offset + n).abs() <= MAX_OFFSET => {
+                offset += n;
+                lowest = lowest.min(offset);
+            }
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+
+    // the loop runs as many times as the counter's value when counting down, or its negative
+    // when counting up
+    let direction = match cells.iter().find(|&&(o, _)| o == 0) {
+        Some(&(_, 255)) => 1u8,
+        Some(&(_, 1)) => 255u8,
+        _ => return None,
+    };
+    let cells: Vec<(isize, u8)> = cells
+        .into_iter()
+        .filter(|&(o, n)| o != 0 && n != 0)
+        .map(|(o, n)| (o, n.wrapping_mul(direction)))
+        .collect();
+
+    // the body only runs while the counter isn't zero, so a bounds check can't be kept ahead of
+    // the multiplies; a loop reaching further left than any cell it changes stays a loop instead
+    if lowest < cells.iter().map(|&(o, _)| o).fold(0, isize::min) {
+        return None;
     }
+    Some(cells)
+}
+
+/// Runs of adds, sets and moves become changes to cells at offsets from the cursor, in the order
+/// the cells were first changed, and then a single move. A run which moves further left than any
+/// cell it changes or where it ends up keeps a bounds check there, so that it still fails if that
+/// is off the tape.
+pub fn fold_offsets(nodes: Vec<Node>) -> Vec<Node> {
+    map_bodies(nodes, &|nodes| {
+        let mut output = vec![];
+        let mut block: Vec<Node> = vec![];
+        // how far the nodes in `block` move
+        let mut moved = 0;
+        for node in nodes {
+            let n = match node.ir {
+                IR1::Add { offset: 0, .. } | IR1::Set { offset: 0, .. } => 0,
+                IR1::Move(n) => n,
+                _ => {
+                    output.extend(fold_block_nodes(std::mem::take(&mut block)));
+                    moved = 0;
+                    output.push(node);
+                    continue;
+                }
+            };
+            if (moved + n).abs() > MAX_OFFSET {
+                output.extend(fold_block_nodes(std::mem::take(&mut block)));
+                moved = 0;
+            }
+            moved += n;
+            block.push(node);
+        }
+        output.extend(fold_block_nodes(block));
+        output
+    })
+}
+
+// (offset, whether the cell is set rather than added to, value) for each cell changed by a block,
+// how far the block moves in total, and the lowest offset it moves to
+type Block = (Vec<(isize, bool, u8)>, isize, isize);
+
+fn fold_block(block: &[Node]) -> Option<Block> {
+    let mut offset = 0;
+    let mut lowest = 0;
+    let mut cells: Vec<(isize, bool, u8)> = vec![];
+    for node in block {
+        let (set, n) = match node.ir {
+            IR1::Move(n) => {
+                offset += n;
+                lowest = lowest.min(offset);
+                continue;
+            }
+            IR1::Add { offset: 0, n } => (false, n),
+            IR1::Set { offset: 0, n } => (true, n),
+            _ => return None,
+        };
+        match cells.iter_mut().find(|&&mut (o, _, _)| o == offset) {
+            Some(cell) if set => *cell = (offset, true, n),
+            Some(cell) => cell.2 = cell.2.wrapping_add(n),
+            None => cells.push((offset, set, n)),
+        }
+    }
+    Some((cells, offset, lowest))
+}
+
+fn fold_block_nodes(block: Vec<Node>) -> Vec<Node> {
+    let (first, last) = match (block.first(), block.last()) {
+        (Some(first), Some(last)) => (first.span.start, last.span.end),
+        _ => return vec![],
+    };
+    let span = SourceSpan::new(first, last);
+    let (cells, moved, lowest) =
+        fold_block(&block).expect("only foldable nodes are put in a block");
+
+    let mut nodes: Vec<Node> = cells
+        .into_iter()
+        .filter(|&(_, set, n)| set || n != 0)
+        .map(|(offset, set, n)| {
+            let ir = if set {
+                IR1::Set { offset, n }
+            } else {
+                IR1::Add { offset, n }
+            };
+            Node::new(ir, span)
+        })
+        .collect();
+
+    // adding 0 to a cell is the cheapest instruction which checks it is on the tape
+    let checked = nodes
+        .iter()
+        .map(|node| match node.ir {
+            IR1::Add { offset, .. } | IR1::Set { offset, .. } => offset,
+            _ => 0,
+        })
+        .chain([0, moved])
+        .min()
+        .unwrap_or(0);
+    if lowest < checked {
+        nodes.insert(
+            0,
+            Node::new(
+                IR1::Add {
+                    offset: lowest,
+                    n: 0,
+                },
+                span,
+            ),
+        );
+    }
+
+    if moved != 0 {
+        nodes.push(Node::new(IR1::Move(moved), span));
+    }
+    nodes
+}
+
+/// Run the optimization passes for `opt_level`
+pub fn optimize(nodes: Vec<Node>, opt_level: OptLevel) -> Vec<Node> {
+    match opt_level {
+        OptLevel::O0 => return nodes,
+        OptLevel::O1 => return merge_runs(nodes),
+        OptLevel::O2 | OptLevel::O3 => (),
+    }
+    let nodes = clear_loops(nodes);
+    let nodes = scan_loops(nodes);
+    let nodes = multiply_loops(nodes);
+    fold_offsets(nodes)
+}
+
+// Write an instruction with a 2 byte offset and a 1 byte value
+fn emit_relative(bytecode: &mut Tape<u8>, op: Opcode, offset: isize, n: u8) {
+    bytecode.push(op.into());
+    bytecode.push_int(2, offset as u16 as u32);
+    bytecode.push(n);
+}
+
+// The distance of a move which fits in MoveTapeShort, which superinstructions are made with
+fn short_move(ir: &IR1) -> Option<i8> {
+    match *ir {
+        IR1::Move(n) if n >= i8::MIN as isize && n <= i8::MAX as isize => Some(n as i8),
+        _ => None,
+    }
+}
+
+/// Write out nodes as bytecode, recording where each one came from. With `fuse`, common pairs
+/// of instructions are written as one superinstruction.
+pub fn emit(nodes: &[Node], bytecode: &mut Tape<u8>, source_map: &mut SourceMap, fuse: bool) {
+    use self::Opcode::*;
+
+    let mut nodes = nodes.iter().peekable();
+    while let Some(node) = nodes.next() {
+        source_map.insert(bytecode.len(), node.span);
+        if let IR1::Add { offset: 0, n } = node.ir {
+            match nodes.peek().and_then(|next| short_move(&next.ir)) {
+                Some(m) if fuse => {
+                    nodes.next();
+                    bytecode.push(SubImmediateMoveTapeShort.into());
+                    bytecode.push(n.wrapping_neg());
+                    bytecode.push(m as u8);
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        match node.ir {
+            IR1::Add { offset: 0, n: 1 } => bytecode.push(Inc.into()),
+            IR1::Add { offset: 0, n: 255 } => bytecode.push(Dec.into()),
+            IR1::Add { offset: 0, n } => {
+                bytecode.push(SubImmediate.into());
+                bytecode.push(n.wrapping_neg());
+            }
+            IR1::Add { offset, n } => {
+                emit_relative(bytecode, SubImmediateRelativeLong, offset, n.wrapping_neg())
+            }
+            IR1::Set { offset: 0, n } => {
+                bytecode.push(Set.into());
+                bytecode.push(n);
+            }
+            IR1::Set { offset, n } => emit_relative(bytecode, SetRelativeLong, offset, n),
+            IR1::Move(1) => bytecode.push(IncTape.into()),
+            IR1::Move(-1) => bytecode.push(DecTape.into()),
+            IR1::Move(n) if n >= i8::MIN as isize && n <= i8::MAX as isize => {
+                bytecode.push(MoveTapeShort.into());
+                bytecode.push(n as u8);
+            }
+            IR1::Move(n) => {
+                bytecode.push(MoveTapeLong.into());
+                bytecode.push_int(2, n as u16 as u32);
+            }
+            IR1::OutputByte => bytecode.push(OutputByte.into()),
+            IR1::InputByte => bytecode.push(InputByte.into()),
+            IR1::Seek(1) => bytecode.push(SeekRight.into()),
+            IR1::Seek(-1) => bytecode.push(SeekLeft.into()),
+            IR1::Seek(stride) if stride > 0 => {
+                bytecode.push(SeekRightStride.into());
+                bytecode.push(stride as u8);
+            }
+            IR1::Seek(stride) => {
+                bytecode.push(SeekLeftStride.into());
+                bytecode.push(-stride as u8);
+            }
+            IR1::MulAdd { offset, factor } => {
+                emit_relative(bytecode, MulSubRelativeLong, offset, factor.wrapping_neg())
+            }
+            IR1::Loop(ref body) => {
+                bytecode.push(JumpAbsoluteIfZero.into());
+                bytecode.push_int(4, 0);
+                let start = bytecode.len();
+                match body.split_last() {
+                    Some((last, rest)) if fuse && short_move(&last.ir).is_some() => {
+                        emit(rest, bytecode, source_map, fuse);
+                        source_map.insert(bytecode.len(), last.span);
+                        bytecode.push(MoveTapeShortJumpAbsoluteIfNonzero.into());
+                        bytecode.push(short_move(&last.ir).unwrap() as u8);
+                    }
+                    _ => {
+                        emit(body, bytecode, source_map, fuse);
+                        bytecode.push(JumpAbsoluteIfNonzero.into());
+                    }
+                }
+                bytecode.push_int(4, start as u32);
+                let end = bytecode.len();
+                bytecode
+                    .write_int_at(start - 4, 4, end as u32)
+                    .expect("loop start was not emitted");
+            }
+        }
+    }
+}
+
+/// Compile source all the way to bytecode
+pub fn compile(
+    sourcecode: &str,
+    opt_level: OptLevel,
+) -> Result<(Tape<u8>, SourceMap), Vec<BracketError>> {
+    let nodes = optimize(parse(sourcecode)?, opt_level);
+
+    let mut bytecode = Tape::new(vec![]);
+    let mut source_map = SourceMap::new();
+
+    // This way the run loop can start with inc_read even
+    // tho the index is a usize (so no negative)
+    // this is a hack
+    bytecode.push(Opcode::Nop.into());
+
+    emit(
+        &nodes,
+        &mut bytecode,
+        &mut source_map,
+        opt_level >= OptLevel::O3,
+    );
+    bytecode.push(Opcode::HaltAlways.into());
+
+    Ok((bytecode, source_map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sourcemap::{SourcePosition, SourceSpan};
+    use super::IR1::*;
+    use super::{
+        clear_loops, emit, fold_offsets, merge_runs, multiply_loops, parse, scan_loops,
+        BracketError,
+    };
+
+    fn irs(nodes: Vec<super::Node>) -> Vec<super::IR1> {
+        nodes.into_iter().map(|node| node.ir).collect()
+    }
+
+    #[test]
+    fn parse_test() {
+        let at = |line, column| SourcePosition { line, column };
+        let nodes = parse("+ [\n-.]").unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[1].span, SourceSpan::new(at(1, 3), at(2, 3)));
+        match nodes[1].ir {
+            Loop(ref body) => assert_eq!(
+                irs(body.clone()),
+                vec![Add { offset: 0, n: 255 }, OutputByte]
+            ),
+            _ => panic!("expected a loop"),
+        }
+
+        assert_eq!(
+            parse("]["),
+            Err(vec![
+                BracketError::Unopened(at(1, 1)),
+                BracketError::Unclosed(at(1, 2)),
+            ])
+        );
+    }
+
+    #[test]
+    fn merge_test() {
+        let nodes = irs(merge_runs(parse("+++-->><<<+-").unwrap()));
+        assert_eq!(nodes, vec![Add { offset: 0, n: 1 }, Move(2), Move(-3)]);
+    }
+
+    #[test]
+    fn loop_pass_test() {
+        let nodes = clear_loops(parse("[-][>>][<]").unwrap());
+        let nodes = irs(scan_loops(nodes));
+        assert_eq!(nodes, vec![Set { offset: 0, n: 0 }, Seek(2), Seek(-1)]);
+
+        let nodes = irs(multiply_loops(parse("[->++>>-<<<][+>+<]").unwrap()));
+        assert_eq!(
+            nodes,
+            vec![
+                MulAdd {
+                    offset: 1,
+                    factor: 2
+                },
+                MulAdd {
+                    offset: 3,
+                    factor: 255
+                },
+                Set { offset: 0, n: 0 },
+                MulAdd {
+                    offset: 1,
+                    factor: 255
+                },
+                Set { offset: 0, n: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn fold_test() {
+        let nodes = clear_loops(parse(">+>++<<-.[>[-]+<]").unwrap());
+        let nodes = irs(fold_offsets(nodes));
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(
+            nodes[..4],
+            [
+                Add { offset: 1, n: 1 },
+                Add { offset: 2, n: 2 },
+                Add { offset: 0, n: 255 },
+                OutputByte,
+            ]
+        );
+        match nodes[4] {
+            Loop(ref body) => assert_eq!(irs(body.clone()), vec![Set { offset: 1, n: 1 }]),
+            _ => panic!("expected a loop"),
+        }
+
+        // moving left and back still fails at the left end of the tape
+        let nodes = irs(fold_offsets(parse("<<>>+>").unwrap()));
+        assert_eq!(
+            nodes,
+            vec![Add { offset: -2, n: 0 }, Add { offset: 0, n: 1 }, Move(1)]
+        );
+        let nodes = irs(fold_offsets(parse("<<+>>+").unwrap()));
+        assert_eq!(
+            nodes,
+            vec![Add { offset: -2, n: 1 }, Add { offset: 0, n: 1 }]
+        );
+    }
+
+    #[test]
+    fn fuse_test() {
+        use super::super::command::Opcode;
+        use super::super::sourcemap::SourceMap;
+        use super::super::tape::Tape;
 
-    let index = 0;
-    while index < v.len() {
+        let nodes = parse("+>[->]").unwrap();
+        let mut bytecode = Tape::new(vec![]);
+        emit(&nodes, &mut bytecode, &mut SourceMap::new(), true);
+        assert_eq!(
+            bytecode.iter().cloned().collect::<Vec<u8>>(),
+            vec![
+                Opcode::SubImmediateMoveTapeShort.into(),
+                255,
+                1,
+                Opcode::JumpAbsoluteIfZero.into(),
+                0,
+                0,
+                0,
+                15,
+                Opcode::Dec.into(),
+                Opcode::MoveTapeShortJumpAbsoluteIfNonzero.into(),
+                1,
+                0,
+                0,
+                0,
+                8,
+            ]
+        );
     }
 }
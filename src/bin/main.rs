@@ -1,17 +1,17 @@
 extern crate stvm;
 
-use stvm::{Lang, STVM};
+use stvm::{EofPolicy, Lang, OptLevel, Program, VmError, VmState, STVM};
 
 use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
 
-fn wait_for_input() -> () {
+fn wait_for_input() {
     use std::io::{self, Read};
     let mut buffer = [0u8; 1];
-    let mut stdin = io::stdin();
-    stdin.lock();
-    match stdin.read(&mut buffer) {
-        Err(e) => panic!("{}", e),
-        _ => (),
+    if let Err(e) = io::stdin().read(&mut buffer) {
+        panic!("{}", e)
     }
 }
 
@@ -28,14 +28,63 @@ impl fmt::Display for ArgError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use ArgError::*;
         match self {
-            Usage => write!(f, "Usage:\n    stvm <script>         Run a script (programming language will be assumed based on file extension)\n    stvm --bf <script>    Run a brainf*ck script"),
-            FileNotFound => write!(f, "File not found{}", ""),
-            UnknownFlag => write!(f, "Unkown flag{}", ""),
+            Usage => write!(f, "Usage:\n    stvm <script>         Run a script (programming language will be assumed based on file extension)\n    stvm run <script>     Same as above; the script may also be a compiled .stvm file\n    stvm compile <script> [-o <out.stvm>] [--strip] [--emit <format>]\n                          Compile a script to an object file, optionally without debug information,\n                          or with --emit c, rust or wat to source code\n    stvm --bf <script>    Run a brainf*ck script\n    stvm --raw <script>   Run an STVM assembly script\n\nOptions:\n    --disasm              Print the compiled bytecode as assembly instead of running it\n    --eof <policy>        What reading past the end of input does: unchanged, 0, 255 or error (default)\n    -O<level>             Optimization level: 0, 1 (default), 2 or 3\n    --max-steps <n>       Stop after executing n opcodes\n    --timeout <seconds>   Stop after running for the given time"),
+            FileNotFound => write!(f, "File not found"),
+            UnknownFlag => write!(f, "Unkown flag"),
             Other(s) => write!(f, "{}", s),
         }
     }
 }
 
+// What `compile` writes out
+enum Emit {
+    Object,
+    C,
+    Rust,
+    Wat,
+}
+
+fn parse_emit(s: &str) -> Option<Emit> {
+    match s {
+        "stvm" | "object" => Some(Emit::Object),
+        "c" => Some(Emit::C),
+        "rust" | "rs" => Some(Emit::Rust),
+        "wat" => Some(Emit::Wat),
+        _ => None,
+    }
+}
+
+fn parse_eof_policy(s: &str) -> Option<EofPolicy> {
+    match s {
+        "unchanged" => Some(EofPolicy::Unchanged),
+        "0" | "zero" => Some(EofPolicy::Zero),
+        "255" | "-1" => Some(EofPolicy::MinusOne),
+        "error" => Some(EofPolicy::Error),
+        _ => None,
+    }
+}
+
+// Point at the source code an instruction was compiled from, if it is known
+fn print_source_location(vm: &STVM, file: &str, offset: usize, show_source: bool) {
+    let span = match vm.source_span(offset) {
+        Some(span) => span,
+        None => return,
+    };
+    eprintln!("    at {}:{}", file, span);
+
+    if show_source {
+        if let Some(line) = vm.sourcecode().lines().nth(span.start.line - 1) {
+            let width = if span.end.line == span.start.line {
+                span.end.column - span.start.column + 1
+            } else {
+                1
+            };
+            eprintln!("    {}", line);
+            eprintln!("    {}{}", " ".repeat(span.start.column - 1), "^".repeat(width));
+        }
+    }
+}
+
 impl fmt::Debug for ArgError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self)
@@ -69,98 +118,259 @@ fn main() -> Result<(), ArgError> {
 
     let mut debug_mode = false;
 
-    let mut i = 0;
-    for argument in env::args() {
+    let mut disassemble = false;
+
+    let mut eof_policy = EofPolicy::default();
+
+    let mut max_steps: Option<u64> = None;
+
+    let mut timeout: Option<Duration> = None;
+
+    let mut output: Option<String> = None;
+
+    let mut strip = false;
+
+    let mut emit = Emit::Object;
+
+    let mut opt_level = OptLevel::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(argument) = args.next() {
         let s = argument.as_ref();
-        if i != 0 {
-            match s {
-                "" => {},
-                _ => match s.chars().next().unwrap() {
-                    '-' => match s {
-                        "--debug" => debug_mode = true,
-                        "--bf" => lang = Some(Lang::Bf),
-                        "--lisp" => lang = Some(Lang::Lisp),
-                        _ => return Err(ArgError::UnknownFlag),
-                    },
-                    _ => positional.push(argument.clone()),
+        match s {
+            "" => {},
+            _ => match s.chars().next().unwrap() {
+                '-' => match s {
+                    "--debug" => debug_mode = true,
+                    "--disasm" => disassemble = true,
+                    "--bf" => lang = Some(Lang::Bf),
+                    "--lisp" => lang = Some(Lang::Lisp),
+                    "--raw" => lang = Some(Lang::Raw),
+                    "--strip" => strip = true,
+                    "-O" | "-O3" => opt_level = OptLevel::O3,
+                    "-O2" => opt_level = OptLevel::O2,
+                    "-O1" => opt_level = OptLevel::O1,
+                    "-O0" => opt_level = OptLevel::O0,
+                    "-o" => {
+                        output = Some(args.next().ok_or(ArgError::Other("-o expects a file name"))?)
+                    }
+                    "--emit" => {
+                        emit = args
+                            .next()
+                            .and_then(|v| parse_emit(&v))
+                            .ok_or(ArgError::Other("--emit expects one of: stvm, c, rust, wat"))?
+                    }
+                    "--eof" => {
+                        eof_policy = args
+                            .next()
+                            .and_then(|v| parse_eof_policy(&v))
+                            .ok_or(ArgError::Other("--eof expects one of: unchanged, 0, 255, error"))?
+                    }
+                    "--max-steps" => {
+                        max_steps = Some(
+                            args.next()
+                                .and_then(|v| v.parse().ok())
+                                .ok_or(ArgError::Other("--max-steps expects a number"))?,
+                        )
+                    }
+                    "--timeout" => {
+                        timeout = Some(
+                            args.next()
+                                .and_then(|v| v.parse::<f64>().ok())
+                                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                                .ok_or(ArgError::Other("--timeout expects a number of seconds"))?,
+                        )
+                    }
+                    _ => return Err(ArgError::UnknownFlag),
                 },
-            };
+                _ => positional.push(argument.clone()),
+            },
         };
-        i += 1;
+    };
+
+    let compile_mode = match positional.first().map(|s| s.as_ref()) {
+        Some("compile") => {
+            positional.remove(0);
+            true
+        }
+        Some("run") => {
+            positional.remove(0);
+            false
+        }
+        _ => false,
     };
 
     if positional.len() > 1 {
         panic!("too many arguments");
     };
 
-    if let None = lang {
-        if positional.len() > 0 {
-            let file = &positional[0];
-            if file.ends_with(".bf") {
-                lang = Some(Lang::Bf);
-            }
-            if file.ends_with(".b") {
-                lang = Some(Lang::Bf);
-            }
+    if lang.is_none() && !positional.is_empty() {
+        let file = &positional[0];
+        if file.ends_with(".bf") {
+            lang = Some(Lang::Bf);
+        }
+        if file.ends_with(".b") {
+            lang = Some(Lang::Bf);
+        }
+        if file.ends_with(".stasm") {
+            lang = Some(Lang::Raw);
         }
     };
 
-    //if positional.len() > 0 {
-        //// the first argument is a filename
-        //let file = &positional[0];
+    if positional.is_empty() {
+        // with no argument, assume program is on stdin?
+        return Err(ArgError::Usage);
+    }
 
-        //if !std::path::Path::new(file).exists() {
-            //return Err(ArgError::FileNotFound)
-        //}
-    //}
+    // the first argument is a filename
+    let file = &positional[0];
 
-    if let Some(lang) = lang {
-        // A language was selected
-        if positional.len() > 0 {
-            // the first argument is a filename
-            let file = &positional[0];
+    if !std::path::Path::new(file).exists() {
+        return Err(ArgError::FileNotFound)
+    }
 
-            if !std::path::Path::new(file).exists() {
-                return Err(ArgError::FileNotFound)
+    let mut program = if lang.is_none() && file.ends_with(".stvm") {
+        // already compiled
+        match Program::load(file) {
+            Ok(program) => program,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
             }
+        }
+    } else if let Some(lang) = lang {
+        let mut program = Program::from_file(lang, file);
+        if let Err(e) = program.compile(opt_level) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        program
+    } else {
+        return Err(ArgError::Other("Could not automatically select source language"));
+    };
 
-            let mut main_vm = STVM::from_file(lang, file);
-
-            if debug_mode {
-                println!("Press enter to run program.");
-                wait_for_input();
+    if compile_mode {
+        if strip {
+            program.strip();
+        }
+        let extension = match emit {
+            Emit::Object => "stvm",
+            Emit::C => "c",
+            Emit::Rust => "rs",
+            Emit::Wat => "wat",
+        };
+        let output = match output {
+            Some(output) => std::path::PathBuf::from(output),
+            None => std::path::Path::new(file).with_extension(extension),
+        };
+        let source = match emit {
+            Emit::Object => {
+                if let Err(e) = program.save(&output) {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                return Ok(());
             }
-
-            let e = main_vm.run();
-            if debug_mode {
-                println!();
-                println!();
+            Emit::C => program.to_c(eof_policy),
+            Emit::Rust => program.to_rust(eof_policy),
+            Emit::Wat => program.to_wat(eof_policy),
+        };
+        match source {
+            Ok(source) => {
+                if let Err(e) = std::fs::write(&output, source) {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
             }
-
-            match e {
-                Err(e) => match e {
-                    _ => eprintln!("{:?}", e),
+            Err(errors) => {
+                eprintln!("Bytecode verification failed:");
+                for e in errors {
+                    eprintln!("    {}", e);
                 }
-                _ => if debug_mode{ println!("OK")},
+                process::exit(1);
             }
+        }
+        return Ok(());
+    }
+
+    let mut main_vm = STVM::from_program(program);
+    main_vm.set_eof_policy(eof_policy);
+
+    if disassemble {
+        print!("{}", main_vm.disassemble());
+        return Ok(());
+    }
+
+    // decoding checks the bytecode first, so that it can be run without any further checks
+    if let Err(errors) = main_vm.decode() {
+        eprintln!("Bytecode verification failed:");
+        for e in errors {
+            eprintln!("    {}", e);
+            print_source_location(&main_vm, file, e.offset, debug_mode);
+        }
+        process::exit(1);
+    }
 
+    // the program was already verified by decoding it; where there is no JIT it is interpreted
+    #[cfg(feature = "jit")]
+    let _ = main_vm.enable_jit();
+
+    if debug_mode {
+        println!("Press enter to run program.");
+        wait_for_input();
+    }
+
+    if let Some(duration) = timeout {
+        let token = main_vm.cancel_token();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            token.cancel();
+        });
+    }
+
+    let e = match max_steps {
+        Some(n) => main_vm.run_with_budget(n),
+        None => main_vm.run().map(|_| VmState::Halt),
+    };
+    if debug_mode {
+        println!();
+        println!();
+    }
+
+    let failed = match e {
+        Err(VmError::Interrupted) => {
+            eprintln!("Timed out");
+            true
+        }
+        Err(e) => {
+            eprintln!("{:?}", e);
+            if let Some(offset) = e.location() {
+                print_source_location(&main_vm, file, offset, debug_mode);
+            }
+            true
+        }
+        Ok(VmState::OutOfFuel) => {
+            eprintln!("Stopped after {} steps", max_steps.unwrap_or(0));
+            true
+        }
+        _ => {
             if debug_mode {
-                println!("{:?}", main_vm);
+                println!("OK")
             }
-            //println!("{:?} {:?}", lang, file);
-        //} else {
-            // with no argument, assume program is on stdin?
-        };
-        Ok(())
-    } else {
-        // with no language selected, what?
-        if positional.len() == 1 {
-            Err(ArgError::Other("Could not automatically select source language"))
-        } else {
-            Err(ArgError::Usage)
+            false
         }
+    };
+
+    if debug_mode {
+        println!("{:?}", main_vm);
+    }
+
+    if failed {
+        process::exit(1);
     }
 
+    Ok(())
+
 /*
     let zero = 0u8;
     let neg_two = -2i8;
@@ -25,7 +25,7 @@ pub enum Opcode {
     MoveTapeShort,
     MoveTapeLong,
 
-    // Move the tape until a non-zero cell is found.
+    // Move the tape until a zero cell is found.
     SeekRight,
     SeekLeft,
 
@@ -36,6 +36,24 @@ pub enum Opcode {
     Pop,
     PushRand,
 
+    // Like SeekRight and SeekLeft, moving by the operand at a time.
+    SeekRightStride,
+    SeekLeftStride,
+
+    // Subtract the current cell times the second operand from the cell at the first operand's
+    // offset. Does nothing if the current cell is zero.
+    MulSubRelativeLong,
+
+    // Subtract the second operand from, or set to it, the cell at the first operand's offset.
+    SubImmediateRelativeLong,
+    SetRelativeLong,
+
+    // Superinstructions, each doing the work of a common pair of instructions in one dispatch.
+    // SubImmediate then MoveTapeShort.
+    SubImmediateMoveTapeShort,
+    // MoveTapeShort then JumpAbsoluteIfNonzero, which ends most loops that move the tape.
+    MoveTapeShortJumpAbsoluteIfNonzero,
+
     // This opcode is always illegal to execute.
     // UNSAFE: Due to the way conversion to the binary representation is implemented, no Opcode can be
     // listed after this one, nor otherwise be assigned a higher integer
@@ -43,6 +61,8 @@ pub enum Opcode {
 }
 
 impl Opcode {
+    // Length of the instruction in bytes, including the opcode itself
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         use self::Opcode::*;
         match self {
@@ -56,14 +76,109 @@ impl Opcode {
             | JumpRelativeShortIfNonzero
             | SubImmediate
             | MoveTapeShort
-            | Set => 2,
+            | Set
+            | SeekRightStride
+            | SeekLeftStride => 2,
+
+            SubImmediateMoveTapeShort => 3,
 
             JumpRelativeLongIfZero | JumpRelativeLongIfNonzero | SubRelativeLong | MoveTapeLong => {
                 3
             }
 
+            MulSubRelativeLong | SubImmediateRelativeLong | SetRelativeLong => 4,
+
             JumpAbsoluteIfZero | JumpAbsoluteIfNonzero => 5,
+
+            MoveTapeShortJumpAbsoluteIfNonzero => 6,
+        }
+    }
+
+    // Size in bytes of each operand, in the order they follow the opcode
+    pub fn operand_widths(&self) -> &'static [usize] {
+        match self {
+            Opcode::MulSubRelativeLong
+            | Opcode::SubImmediateRelativeLong
+            | Opcode::SetRelativeLong => &[2, 1],
+            Opcode::SubImmediateMoveTapeShort => &[1, 1],
+            Opcode::MoveTapeShortJumpAbsoluteIfNonzero => &[1, 4],
+            _ => match self.len() {
+                1 => &[],
+                2 => &[1],
+                3 => &[2],
+                _ => &[4],
+            },
+        }
+    }
+
+    // Whether the operand bytes hold a signed number
+    pub fn has_signed_operand(&self) -> bool {
+        use self::Opcode::*;
+        matches!(
+            self,
+            SubImmediate
+                | SubRelativeLong
+                | MulSubRelativeLong
+                | SubImmediateRelativeLong
+                | SetRelativeLong
+                | SubImmediateMoveTapeShort
+                | MoveTapeShort
+                | MoveTapeShortJumpAbsoluteIfNonzero
+                | MoveTapeLong
+                | JumpRelativeShortIfZero
+                | JumpRelativeShortIfNonzero
+                | JumpRelativeLongIfZero
+                | JumpRelativeLongIfNonzero
+        )
+    }
+
+    // Whether the operand is a jump offset, counted from the start of the next instruction
+    pub fn is_relative_jump(&self) -> bool {
+        use self::Opcode::*;
+        matches!(
+            self,
+            JumpRelativeShortIfZero
+                | JumpRelativeShortIfNonzero
+                | JumpRelativeLongIfZero
+                | JumpRelativeLongIfNonzero
+        )
+    }
+
+    // Whether the last operand is the location to jump to
+    pub fn is_absolute_jump(&self) -> bool {
+        matches!(
+            self,
+            Opcode::JumpAbsoluteIfZero
+                | Opcode::JumpAbsoluteIfNonzero
+                | Opcode::MoveTapeShortJumpAbsoluteIfNonzero
+        )
+    }
+
+    // Turn the bytes following this opcode into the numbers they stand for
+    pub fn decode_operands(&self, bytes: &[u8]) -> Vec<i64> {
+        let mut operands = vec![];
+        let mut bytes = bytes.iter();
+        for &width in self.operand_widths() {
+            let bits = bytes
+                .by_ref()
+                .take(width)
+                .fold(0u32, |n, &byte| (n << 8) | byte as u32);
+            let n = if self.has_signed_operand() && width < 4 {
+                let shift = 32 - 8 * width as u32;
+                ((bits << shift) as i32 >> shift) as i64
+            } else {
+                bits as i64
+            };
+            operands.push(n);
         }
+        operands
+    }
+
+    // Look up an opcode by its mnemonic, which is the same as its name in this enum
+    pub fn from_name(name: &str) -> Option<Opcode> {
+        (0..Opcode::Illegal as u8)
+            .map(Opcode::from)
+            .find(|op| format!("{:?}", op).eq_ignore_ascii_case(name))
     }
 
     pub fn from_i8(n: i8) -> Option<Opcode> {
@@ -0,0 +1,218 @@
+/*!
+ * Decoding of bytecode into typed instructions
+ *
+ * The VM can run bytecode a byte at a time, decoding each instruction as it
+ * gets to it, or decode the whole program up front with `decode` and run the
+ * resulting `DecodedProgram`, in which jumps point at instruction indices
+ * instead of byte offsets. Either way the same `Instruction`s are executed.
+ */
+
+use std::collections::HashMap;
+
+use super::command::Opcode;
+use super::tape::Tape;
+use super::verify::{verify, VerifyError};
+use super::VmError;
+
+/// One instruction with its operands. Jump targets are byte offsets when
+/// decoded with `decode_at`, and instruction indices in a `DecodedProgram`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Nop,
+    // `Inc`, `Dec` and `SubImmediate` all subtract from the current cell
+    Sub(u8),
+    // Subtract the cell at an offset from the current cell
+    SubRelative(isize),
+    // Subtract from the cell at an offset
+    SubImmediateRelative(isize, u8),
+    Set(u8),
+    SetRelative(isize, u8),
+    MulSubRelative(isize, u8),
+    MoveTape(isize),
+    // Superinstructions
+    SubMoveTape(u8, isize),
+    MoveTapeJumpIfNonzero(isize, usize),
+    // Move by the stride, left if negative, until on a zero cell
+    Seek(isize),
+    JumpIfZero(usize),
+    JumpIfNonzero(usize),
+    OutputByte,
+    InputByte,
+    HaltAlways,
+    HaltIfNotEqual(u8),
+    Push,
+    Pop,
+    PushRand,
+}
+
+/// Bytecode decoded all at once, ready to be run without looking at any bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedProgram {
+    pub instructions: Vec<Instruction>,
+    // byte offset each instruction was decoded from, for error messages and source maps
+    pub offsets: Vec<usize>,
+}
+
+impl DecodedProgram {
+    /// Index of the instruction at a byte offset, if one starts there
+    pub fn index_of(&self, offset: usize) -> Option<usize> {
+        self.offsets.binary_search(&offset).ok()
+    }
+}
+
+/// Decode the instruction at `offset`, returning it along with its length in bytes
+pub fn decode_at(bytecode: &Tape<u8>, offset: usize) -> Result<(Instruction, usize), VmError> {
+    use self::Instruction::*;
+
+    let byte = bytecode
+        .peek_at(offset)
+        .map_err(|_| VmError::UnexpectedEof)?;
+    let op = Opcode::from_u8(byte).ok_or(VmError::InvalidOperation(byte, offset))?;
+    let length = op.len();
+    if offset + length > bytecode.len() {
+        return Err(VmError::UnexpectedEof);
+    }
+    // operands are read in place at fixed widths, big-endian, as this runs for every step when
+    // stepping through bytecode; `Opcode::decode_operands` is the general form
+    let operands = &bytecode.as_slice()[offset + 1..offset + length];
+    let byte = |i: usize| operands[i];
+    let signed_byte = |i: usize| operands[i] as i8 as isize;
+    let short = |i: usize| i16::from_be_bytes([operands[i], operands[i + 1]]) as isize;
+    let word = |i: usize| {
+        u32::from_be_bytes([
+            operands[i],
+            operands[i + 1],
+            operands[i + 2],
+            operands[i + 3],
+        ]) as usize
+    };
+
+    // relative jumps to before the start can only fail, which `Tape::jump` does for usize::MAX
+    let relative = |n: isize| {
+        let target = (offset + length) as isize + n;
+        if target < 0 {
+            usize::MAX
+        } else {
+            target as usize
+        }
+    };
+
+    let instruction = match op {
+        Opcode::Nop => Nop,
+        Opcode::Inc => Sub(255),
+        Opcode::Dec => Sub(1),
+        Opcode::SubImmediate => Sub(byte(0)),
+        Opcode::SubRelativeLong => SubRelative(short(0)),
+        Opcode::SubImmediateRelativeLong => SubImmediateRelative(short(0), byte(2)),
+        Opcode::Set => Set(byte(0)),
+        Opcode::SetRelativeLong => SetRelative(short(0), byte(2)),
+        Opcode::MulSubRelativeLong => MulSubRelative(short(0), byte(2)),
+        Opcode::IncTape => MoveTape(1),
+        Opcode::DecTape => MoveTape(-1),
+        Opcode::MoveTapeShort => MoveTape(signed_byte(0)),
+        Opcode::MoveTapeLong => MoveTape(short(0)),
+        Opcode::SeekRight => Seek(1),
+        Opcode::SeekLeft => Seek(-1),
+        Opcode::SeekRightStride => Seek(byte(0) as isize),
+        Opcode::SeekLeftStride => Seek(-(byte(0) as isize)),
+        Opcode::JumpRelativeShortIfZero => JumpIfZero(relative(signed_byte(0))),
+        Opcode::JumpRelativeLongIfZero => JumpIfZero(relative(short(0))),
+        Opcode::JumpRelativeShortIfNonzero => JumpIfNonzero(relative(signed_byte(0))),
+        Opcode::JumpRelativeLongIfNonzero => JumpIfNonzero(relative(short(0))),
+        Opcode::JumpAbsoluteIfZero => JumpIfZero(word(0)),
+        Opcode::JumpAbsoluteIfNonzero => JumpIfNonzero(word(0)),
+        Opcode::SubImmediateMoveTapeShort => SubMoveTape(byte(0), signed_byte(1)),
+        Opcode::MoveTapeShortJumpAbsoluteIfNonzero => {
+            MoveTapeJumpIfNonzero(signed_byte(0), word(1))
+        }
+        Opcode::OutputByte => OutputByte,
+        Opcode::InputByte => InputByte,
+        Opcode::HaltAlways => HaltAlways,
+        Opcode::HaltIfNotEqual => HaltIfNotEqual(byte(0)),
+        Opcode::Push => Push,
+        Opcode::Pop => Pop,
+        Opcode::PushRand => PushRand,
+        Opcode::StartLoop | Opcode::EndLoop | Opcode::Illegal => {
+            return Err(VmError::UnexpectedCommand(op))
+        }
+    };
+    Ok((instruction, length))
+}
+
+/// Decode a whole program, which has to pass `verify` first
+pub fn decode(bytecode: &Tape<u8>) -> Result<DecodedProgram, Vec<VerifyError>> {
+    verify(bytecode)?;
+
+    let mut instructions = vec![];
+    let mut offsets = vec![];
+    let mut offset = 0;
+    while offset < bytecode.len() {
+        let (instruction, length) =
+            decode_at(bytecode, offset).expect("verified bytecode failed to decode");
+        instructions.push(instruction);
+        offsets.push(offset);
+        offset += length;
+    }
+
+    // verification made sure that every jump lands on an instruction
+    let indices: HashMap<usize, usize> = offsets.iter().enumerate().map(|(i, &o)| (o, i)).collect();
+    for instruction in instructions.iter_mut() {
+        match instruction {
+            Instruction::JumpIfZero(target)
+            | Instruction::JumpIfNonzero(target)
+            | Instruction::MoveTapeJumpIfNonzero(_, target) => {
+                *target = indices[target];
+            }
+            _ => (),
+        }
+    }
+
+    Ok(DecodedProgram {
+        instructions,
+        offsets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::command::Opcode;
+    use super::super::command::Opcode::*;
+    use super::super::tape::Tape;
+    use super::Instruction::*;
+    use super::{decode, decode_at};
+
+    #[test]
+    fn decode_test() {
+        let bytecode = Tape::new(vec![
+            Opcode::Nop.into(),
+            SubImmediate.into(),
+            -3i8 as u8,
+            MulSubRelativeLong.into(),
+            0xff,
+            0xfe,
+            2,
+            JumpRelativeShortIfNonzero.into(),
+            -6i8 as u8,
+            SeekLeftStride.into(),
+            3,
+            Opcode::HaltAlways.into(),
+        ]);
+        assert_eq!(decode_at(&bytecode, 7).unwrap(), (JumpIfNonzero(3), 2));
+
+        let decoded = decode(&bytecode).unwrap();
+        assert_eq!(
+            decoded.instructions,
+            vec![
+                super::Instruction::Nop,
+                Sub(253),
+                MulSubRelative(-2, 2),
+                JumpIfNonzero(2),
+                Seek(-3),
+                super::Instruction::HaltAlways,
+            ]
+        );
+        assert_eq!(decoded.offsets, vec![0, 1, 3, 7, 9, 11]);
+
+        assert!(decode(&Tape::new(vec![StartLoop.into()])).is_err());
+    }
+}
@@ -0,0 +1,486 @@
+/*!
+ * Translation of decoded programs to x86-64 machine code
+ *
+ * The machine code only does what can be done without leaving the tape as
+ * it is: arithmetic, moves, seeks and jumps. Anything else, including a
+ * move past the end of the tape, makes it return the index of the
+ * instruction it stopped at, which the interpreter then executes before
+ * calling back in. Errors and tape growth are therefore exactly as in the
+ * interpreter.
+ *
+ * The generated function is called with the System V calling convention:
+ *
+ * ```text
+ * rdi  tape data        rsi  tape length       rdx  pointer to the cursor
+ * rcx  instruction to start at                 r8   pointer to the cancel flag
+ * ```
+ *
+ * and keeps the cursor in rax, using r10 and r11 as scratch registers. The
+ * cancel flag is checked on every backward jump, so that a program stuck in
+ * a loop can still be interrupted.
+ *
+ * On anything other than x86-64 Linux no code is generated, and the VM just
+ * interprets.
+ */
+
+use std::sync::atomic::AtomicBool;
+
+use super::decode::DecodedProgram;
+use super::tape::Tape;
+
+/// Machine code for a whole program
+pub struct JitCode {
+    memory: *mut u8,
+    size: usize,
+}
+
+impl JitCode {
+    /// Generate code for a program, if the machine is one code can be generated for
+    pub fn compile(program: &DecodedProgram) -> Option<JitCode> {
+        imp::compile(program)
+    }
+
+    /// Run from the instruction at index `pc` until reaching one that has to be interpreted,
+    /// returning its index
+    pub fn run(&self, tape: &mut Tape<u8>, pc: usize, cancel: &AtomicBool) -> usize {
+        imp::run(self, tape, pc, cancel)
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod imp {
+    use std::os::raw::{c_int, c_long, c_void};
+    use std::sync::atomic::AtomicBool;
+
+    use super::super::decode::{DecodedProgram, Instruction};
+    use super::super::tape::Tape;
+    use super::JitCode;
+
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const PROT_EXEC: c_int = 4;
+    const MAP_PRIVATE: c_int = 2;
+    const MAP_ANONYMOUS: c_int = 0x20;
+    const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: c_long,
+        ) -> *mut c_void;
+        fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+
+    type Entry = extern "sysv64" fn(*mut u8, usize, *mut usize, usize, *const AtomicBool) -> usize;
+
+    // Machine code being written, with jumps to instructions filled in at the end
+    struct Assembler {
+        code: Vec<u8>,
+        // offset of the code for each instruction
+        labels: Vec<usize>,
+        // (offset of a rel32, instruction index it jumps to)
+        fixups: Vec<(usize, usize)>,
+    }
+
+    impl Assembler {
+        fn emit(&mut self, bytes: &[u8]) {
+            self.code.extend_from_slice(bytes);
+        }
+
+        fn emit_i32(&mut self, n: i32) {
+            self.code.extend_from_slice(&n.to_le_bytes());
+        }
+
+        // Leave a rel32 to be filled in with the distance to `target`, returning its offset
+        fn emit_rel32(&mut self) -> usize {
+            self.code.extend_from_slice(&[0; 4]);
+            self.code.len() - 4
+        }
+
+        fn patch_rel32(&mut self, at: usize, target: usize) {
+            let rel = target as i32 - (at + 4) as i32;
+            self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+
+        // Store the cursor and return `index` as the instruction to interpret
+        fn exit(&mut self, index: usize) {
+            // mov [rdx], rax; mov eax, index; ret
+            self.emit(&[0x48, 0x89, 0x02, 0xb8]);
+            self.emit_i32(index as i32);
+            self.emit(&[0xc3]);
+        }
+
+        // Put the index of the cell `offset` away from the cursor in r11, exiting at `index` if it
+        // is not on the tape
+        fn check_cell(&mut self, offset: isize, index: usize) {
+            // lea r11, [rax + offset]; cmp r11, rsi; jb past the exit
+            self.emit(&[0x4c, 0x8d, 0x98]);
+            self.emit_i32(offset as i32);
+            self.emit(&[0x49, 0x39, 0xf3, 0x72, 0x09]);
+            self.exit(index);
+        }
+
+        // Exit at `index` if the VM has been cancelled
+        fn check_cancel(&mut self, index: usize) {
+            // cmp byte [r8], 0; je past the exit
+            self.emit(&[0x41, 0x80, 0x38, 0x00, 0x74, 0x09]);
+            self.exit(index);
+        }
+
+        // Jump to an instruction, with `opcode` being a jmp or jcc taking a rel32
+        fn jump(&mut self, opcode: &[u8], target: usize) {
+            self.emit(opcode);
+            let at = self.emit_rel32();
+            self.fixups.push((at, target));
+        }
+
+        fn instruction(&mut self, instruction: Instruction, index: usize) {
+            use super::super::decode::Instruction::*;
+
+            const JMP: &[u8] = &[0xe9];
+            const JE: &[u8] = &[0x0f, 0x84];
+            const JNE: &[u8] = &[0x0f, 0x85];
+            // cmp byte [rdi + rax], 0
+            const TEST_CURRENT: &[u8] = &[0x80, 0x3c, 0x07, 0x00];
+
+            match instruction {
+                Nop => (),
+                // sub byte [rdi + rax], n
+                Sub(n) => self.emit(&[0x80, 0x2c, 0x07, n]),
+                SubRelative(offset) => {
+                    self.check_cell(offset, index);
+                    // movzx r10d, byte [rdi + r11]; sub byte [rdi + rax], r10b
+                    self.emit(&[0x46, 0x0f, 0xb6, 0x14, 0x1f, 0x44, 0x28, 0x14, 0x07]);
+                }
+                SubImmediateRelative(offset, n) => {
+                    self.check_cell(offset, index);
+                    // sub byte [rdi + r11], n
+                    self.emit(&[0x42, 0x80, 0x2c, 0x1f, n]);
+                }
+                // mov byte [rdi + rax], n
+                Set(n) => self.emit(&[0xc6, 0x04, 0x07, n]),
+                SetRelative(offset, n) => {
+                    self.check_cell(offset, index);
+                    // mov byte [rdi + r11], n
+                    self.emit(&[0x42, 0xc6, 0x04, 0x1f, n]);
+                }
+                MulSubRelative(offset, factor) => {
+                    // movzx r10d, byte [rdi + rax]; test r10d, r10d; jz past the rest
+                    self.emit(&[0x44, 0x0f, 0xb6, 0x14, 0x07, 0x45, 0x85, 0xd2]);
+                    self.emit(JE);
+                    let skip = self.emit_rel32();
+                    self.check_cell(offset, index);
+                    // imul r10d, r10d, factor; sub byte [rdi + r11], r10b
+                    self.emit(&[0x45, 0x69, 0xd2]);
+                    self.emit_i32(factor as i32);
+                    self.emit(&[0x46, 0x28, 0x14, 0x1f]);
+                    let end = self.code.len();
+                    self.patch_rel32(skip, end);
+                }
+                MoveTape(n) => {
+                    self.check_cell(n, index);
+                    // mov rax, r11
+                    self.emit(&[0x4c, 0x89, 0xd8]);
+                }
+                SubMoveTape(n, m) => {
+                    // the move is checked first, so that exiting leaves the cell alone
+                    self.check_cell(m, index);
+                    self.emit(&[0x80, 0x2c, 0x07, n, 0x4c, 0x89, 0xd8]);
+                }
+                Seek(stride) => {
+                    // a seek off the tape leaves the cursor where it started, so that is kept
+                    // in r10 to go back to before exiting
+                    // mov r10, rax
+                    self.emit(&[0x49, 0x89, 0xc2]);
+                    let top = self.code.len();
+                    self.emit(TEST_CURRENT);
+                    self.emit(JE);
+                    let done = self.emit_rel32();
+                    // lea r11, [rax + stride]; cmp r11, rsi; jb past the exit; mov rax, r10
+                    self.emit(&[0x4c, 0x8d, 0x98]);
+                    self.emit_i32(stride as i32);
+                    self.emit(&[0x49, 0x39, 0xf3, 0x72, 0x0c, 0x4c, 0x89, 0xd0]);
+                    self.exit(index);
+                    // mov rax, r11
+                    self.emit(&[0x4c, 0x89, 0xd8]);
+                    self.emit(JMP);
+                    let back = self.emit_rel32();
+                    self.patch_rel32(back, top);
+                    let end = self.code.len();
+                    self.patch_rel32(done, end);
+                }
+                JumpIfZero(target) | JumpIfNonzero(target) => {
+                    if target <= index {
+                        self.check_cancel(index);
+                    }
+                    self.emit(TEST_CURRENT);
+                    let condition = if let JumpIfZero(_) = instruction {
+                        JE
+                    } else {
+                        JNE
+                    };
+                    self.jump(condition, target);
+                }
+                MoveTapeJumpIfNonzero(n, target) => {
+                    if target <= index {
+                        self.check_cancel(index);
+                    }
+                    self.check_cell(n, index);
+                    self.emit(&[0x4c, 0x89, 0xd8]);
+                    self.emit(TEST_CURRENT);
+                    self.jump(JNE, target);
+                }
+                OutputByte | InputByte | HaltAlways | HaltIfNotEqual(_) | Push | Pop | PushRand => {
+                    self.exit(index)
+                }
+            }
+        }
+    }
+
+    pub fn compile(program: &DecodedProgram) -> Option<JitCode> {
+        let count = program.instructions.len();
+        let mut asm = Assembler {
+            code: vec![],
+            labels: vec![],
+            fixups: vec![],
+        };
+
+        // mov rax, [rdx]; lea r11, [rip + table]
+        asm.emit(&[0x48, 0x8b, 0x02, 0x4c, 0x8d, 0x1d]);
+        let table_rel = asm.emit_rel32();
+        // movsxd r10, dword [r11 + rcx * 4]; add r10, r11; jmp r10
+        asm.emit(&[0x4d, 0x63, 0x14, 0x8b, 0x4d, 0x01, 0xda, 0x41, 0xff, 0xe2]);
+
+        for (index, &instruction) in program.instructions.iter().enumerate() {
+            asm.labels.push(asm.code.len());
+            asm.instruction(instruction, index);
+        }
+        // running off the end is left for the interpreter to report
+        asm.labels.push(asm.code.len());
+        asm.exit(count);
+
+        for (at, target) in std::mem::take(&mut asm.fixups) {
+            let label = asm.labels[target];
+            asm.patch_rel32(at, label);
+        }
+
+        // jump table of offsets from its start to the code for each instruction
+        while !asm.code.len().is_multiple_of(4) {
+            asm.emit(&[0xcc]);
+        }
+        let table = asm.code.len();
+        asm.patch_rel32(table_rel, table);
+        for i in 0..=count {
+            let label = asm.labels[i];
+            asm.emit_i32(label as i32 - table as i32);
+        }
+
+        let size = asm.code.len();
+        unsafe {
+            let memory = mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if memory == MAP_FAILED {
+                return None;
+            }
+            std::ptr::copy_nonoverlapping(asm.code.as_ptr(), memory as *mut u8, size);
+            if mprotect(memory, size, PROT_READ | PROT_EXEC) != 0 {
+                munmap(memory, size);
+                return None;
+            }
+            Some(JitCode {
+                memory: memory as *mut u8,
+                size,
+            })
+        }
+    }
+
+    pub fn run(code: &JitCode, tape: &mut Tape<u8>, pc: usize, cancel: &AtomicBool) -> usize {
+        let mut cursor = tape.get_cursor();
+        let len = tape.len();
+        let data = tape.as_mut_slice().as_mut_ptr();
+        let pc = unsafe {
+            let entry: Entry = std::mem::transmute(code.memory);
+            entry(data, len, &mut cursor, pc, cancel)
+        };
+        tape.jump(cursor)
+            .expect("machine code moved the cursor off the tape");
+        pc
+    }
+
+    pub fn free(code: &mut JitCode) {
+        unsafe {
+            munmap(code.memory as *mut c_void, code.size);
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+mod imp {
+    use std::sync::atomic::AtomicBool;
+
+    use super::super::decode::DecodedProgram;
+    use super::super::tape::Tape;
+    use super::JitCode;
+
+    pub fn compile(_program: &DecodedProgram) -> Option<JitCode> {
+        None
+    }
+
+    pub fn run(_code: &JitCode, _tape: &mut Tape<u8>, pc: usize, _cancel: &AtomicBool) -> usize {
+        pc
+    }
+
+    pub fn free(_code: &mut JitCode) {}
+}
+
+impl Drop for JitCode {
+    fn drop(&mut self) {
+        imp::free(self);
+    }
+}
+
+impl std::fmt::Debug for JitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "JitCode({} bytes)", self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::prng::Prng;
+    use super::super::tests::SharedBuffer;
+    use super::super::{EofPolicy, Lang, OptLevel, Program, VmState, STVM};
+
+    // Everything observable about a finished run
+    #[derive(Debug, PartialEq)]
+    struct Outcome {
+        result: String,
+        output: Vec<u8>,
+        tape: Vec<u8>,
+        cursor: usize,
+    }
+
+    fn vm(source: &str, opt_level: OptLevel, input: &'static [u8]) -> (STVM, SharedBuffer) {
+        let mut program = Program::new(Lang::Bf, source);
+        program.compile(opt_level).unwrap();
+        let output = SharedBuffer::default();
+        let mut vm = STVM::with_io(input, output.clone());
+        vm.set_program(program);
+        vm.set_eof_policy(EofPolicy::Zero);
+        vm.decode().unwrap();
+        (vm, output)
+    }
+
+    fn outcome(vm: &mut STVM, output: SharedBuffer, budget: Option<u64>) -> Outcome {
+        let result = match budget {
+            Some(budget) => vm.run_with_budget(budget),
+            None => vm.run().map(|_| VmState::Halt),
+        };
+        let output = output.0.borrow().clone();
+        Outcome {
+            result: format!("{:?}", result),
+            output,
+            tape: vm.each_cell().cloned().collect(),
+            cursor: vm.get_cursor(),
+        }
+    }
+
+    // Run a program interpreted and as machine code, if it finishes within `budget` steps
+    // when interpreted, and check both do the same
+    fn differ(source: &str, input: &'static [u8], budget: u64) -> bool {
+        for &opt_level in &[OptLevel::O0, OptLevel::O2, OptLevel::O3] {
+            let (mut interpreted, output) = vm(source, opt_level, input);
+            let expected = outcome(&mut interpreted, output, Some(budget));
+            if expected.result == "Ok(OutOfFuel)" {
+                return false;
+            }
+
+            let (mut native, output) = vm(source, opt_level, input);
+            assert!(native.enable_jit().unwrap());
+            let actual = outcome(&mut native, output, None);
+            assert_eq!(actual, expected, "{} at {:?}", source, opt_level);
+        }
+        true
+    }
+
+    #[test]
+    fn differential_test() {
+        let programs: &[(&str, &[u8])] = &[
+            ("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", b""),
+            ("++++++++[>+>++++<<-]>++>>+<[-[>>+<<-]+>>]>+[-<<<[->[+[-]+>++>>>-<<]<[<]>>++++++[<<+++++>>-]+<<++.[-]<<]>.>+[>>]>+]", b""),
+            (">,[>,]<[.<]<<[-]", b"stvm"),
+            ("+<", b""),
+            ("++++++++[>>>>>>>>+<<<<<<<<-]>>>>>>>>[>>+<-]>>>.", b""),
+            ("+++[>>>>+++[-<<+>>]<<<<-]>>[<<+>>-]", b""),
+            ("->>+[-<<[->>>>+<<<<]>>]", b""),
+            ("+++++[->+>>>>++<<<<<]>[[>]<-]", b""),
+        ];
+        for &(source, input) in programs {
+            assert!(
+                differ(source, input, 10_000_000),
+                "{} did not finish",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn fuzz_test() {
+        let mut prng = Prng::new_from_seed(4321);
+        let mut finished = 0;
+        for _ in 0..300 {
+            let mut source = String::from(">>>>");
+            let mut depth = 0;
+            for _ in 0..40 {
+                match prng.gen_u8() % 9 {
+                    0 | 1 => source.push('+'),
+                    2 => source.push('-'),
+                    3 => source.push('>'),
+                    4 => source.push('<'),
+                    5 => source.push('.'),
+                    6 => {
+                        source.push('[');
+                        depth += 1;
+                    }
+                    7 if depth > 0 => {
+                        source.push(']');
+                        depth -= 1;
+                    }
+                    _ => source.push_str("[-]"),
+                }
+            }
+            source.push_str(&"]".repeat(depth));
+            if differ(&source, b"", 100_000) {
+                finished += 1;
+            }
+        }
+        assert!(finished > 100, "only {} programs finished", finished);
+    }
+
+    #[test]
+    fn cancel_test() {
+        let (mut native, _) = vm("+[]", OptLevel::O3, b"");
+        assert!(native.enable_jit().unwrap());
+        let token = native.cancel_token();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            token.cancel();
+        });
+        match native.run() {
+            Err(super::super::VmError::Interrupted) => (),
+            other => panic!("expected to be interrupted, got {:?}", other),
+        }
+        canceller.join().unwrap();
+    }
+}
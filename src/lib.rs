@@ -7,19 +7,49 @@ use prng::Prng;
 
 mod tape;
 use tape::Tape;
+pub use tape::TapeError;
 
 mod command;
-use command::Opcode;
+pub use command::Opcode;
 
 mod lisp;
+pub use lisp::{LispError, LispErrorKind};
 
+mod bf;
+pub use bf::BracketError;
+
+mod decode;
+use decode::{DecodedProgram, Instruction};
+
+mod verify;
+pub use verify::{VerifyError, VerifyErrorKind};
+
+mod asm;
+pub use asm::{AsmError, AsmErrorKind};
+
+mod object;
+pub use object::ObjectError;
+
+mod sourcemap;
+pub use sourcemap::{SourceMap, SourcePosition, SourceSpan};
+
+mod backend;
+
+#[cfg(feature = "jit")]
+mod jit;
+
+use std::fs;
 use std::io::{self, Read, Write};
+use std::path::Path;
 
 use std::error::Error;
 use std::fmt;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 /// Supported languages for compiling
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Lang {
     Raw,
     Bf,
@@ -33,39 +63,139 @@ pub struct Program {
     sourcecode: String,
     //tokenlist, ast, etc?
     bytecode: Tape<u8>,
+    source_map: SourceMap,
+}
+
+/// What `InputByte` does to the current cell once the input is exhausted
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EofPolicy {
+    /// Leave the cell as it was
+    Unchanged,
+    /// Write 0 to the cell
+    Zero,
+    /// Write 255 (-1) to the cell
+    MinusOne,
+    /// Stop the program with `VmError::Io`
+    #[default]
+    Error,
+}
+
+/// How much effort compilation puts into making faster bytecode
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub enum OptLevel {
+    /// One opcode per BF command
+    O0,
+    /// Runs of the same kind of command are merged
+    #[default]
+    O1,
+    /// Loop idioms are recognized and tape moves are folded into offsets
+    O2,
+    /// As O2, also fusing common pairs of instructions into superinstructions
+    O3,
+}
+
+/// Handle which can stop a running VM, possibly from another thread
+///
+/// A cancel is used up by the run it interrupts, so the VM can be run again
+/// afterwards. A cancel while no run is going interrupts the next one. A VM
+/// waiting for `InputByte` to read can not be interrupted until the read returns.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask the VM to stop; `run` will return `VmError::Interrupted` shortly after
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // Clear a cancel once it has interrupted a run
+    fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
 }
 
+// How many opcodes are executed between checks of the cancel token
+const CANCEL_CHECK_INTERVAL: u64 = 1024;
+
 #[derive(Debug)]
 struct RegisterSet {
+    #[allow(dead_code)]
     pub acc: i16,
+    #[allow(dead_code)]
     pub zero: bool,
     pub arithmetic_overflow: bool,
     pub stack_underflow: bool,
     pub tape_outside_right_bound: bool,
 }
 
-#[derive(Debug)]
 pub struct STVM {
     program: Program,
     tape: Tape<u8>,
     stack: Tape<u8>,
     registers: RegisterSet,
-    //input: Vec<i8>, // should be a FIFO tho
-    //output: Vec<i8>,
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+    eof_policy: EofPolicy,
+    cancel: CancelToken,
     prng: Prng,
+    // the program decoded up front, if running that way, and the index of the next instruction
+    decoded: Option<DecodedProgram>,
+    pc: usize,
+    // instructions dispatched so far, which superinstructions are meant to cut down
+    dispatched: u64,
+    // machine code for the decoded program, if it has been generated
+    #[cfg(feature = "jit")]
+    jit: Option<jit::JitCode>,
+}
+
+// What happens after an instruction is executed
+enum Flow {
+    Continue,
+    // go to a byte offset, or an instruction index in a decoded program
+    Jump(usize),
+    Halt,
+}
+
+impl fmt::Debug for STVM {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the I/O streams are trait objects, so they are left out
+        f.debug_struct("STVM")
+            .field("program", &self.program)
+            .field("tape", &self.tape)
+            .field("stack", &self.stack)
+            .field("registers", &self.registers)
+            .field("eof_policy", &self.eof_policy)
+            .field("cancel", &self.cancel)
+            .field("prng", &self.prng)
+            .finish()
+    }
 }
 
 #[derive(Debug)]
 pub enum VmError {
     Halt,
     Io(&'static str),
+    Stream(io::Error),
 
     // byte op and location
     InvalidOperation(u8, usize),
 
     UnexpectedEof,
-    TapeError(tape::TapeError),
+
+    // tape error and the location of the operation which caused it
+    TapeError(tape::TapeError, usize),
     UnexpectedCommand(Opcode),
+
+    /// The VM's cancel token was triggered while running
+    Interrupted,
 }
 
 impl fmt::Display for VmError {
@@ -74,12 +204,14 @@ impl fmt::Display for VmError {
         match self {
             Halt => write!(f, "Halt"),
             Io(s) => write!(f, "I/O Error: {:?}", s),
+            Stream(e) => write!(f, "I/O Error: {}", e),
             InvalidOperation(op_byte, location) => {
                 write!(f, "Invalid Operation {} at position {}", op_byte, location)
             }
             UnexpectedEof => write!(f, "Unexpected EOF"),
-            TapeError(e) => write!(f, "Tape Error: {}", e),
+            TapeError(e, location) => write!(f, "Tape Error: {} at position {}", e, location),
             UnexpectedCommand(op) => write!(f, "Unexpected Coommand: {:?}", op),
+            Interrupted => write!(f, "Interrupted"),
         }
     }
 }
@@ -88,9 +220,56 @@ impl Error for VmError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         use self::VmError::*;
         match self {
-            Halt | InvalidOperation(_, _) | UnexpectedCommand(_) | UnexpectedEof => None,
-            TapeError(e) => Some(e),
+            Halt | InvalidOperation(_, _) | UnexpectedCommand(_) | UnexpectedEof | Interrupted => {
+                None
+            }
+            TapeError(e, _) => Some(e),
             Io(_str) => None,
+            Stream(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompileError {
+    // errors in `Lang::Raw` assembly
+    Asm(Vec<AsmError>),
+    // every bracket in BF source which has no partner
+    UnmatchedBrackets(Vec<BracketError>),
+    // errors in `Lang::Lisp` source
+    Lisp(LispError),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CompileError::*;
+        match self {
+            Asm(errors) => {
+                write!(f, "Assembly failed:")?;
+                for e in errors {
+                    write!(f, "\n    {}", e)?;
+                }
+                Ok(())
+            }
+            UnmatchedBrackets(errors) => {
+                write!(f, "Unmatched brackets:")?;
+                for e in errors {
+                    write!(f, "\n    {}", e)?;
+                }
+                Ok(())
+            }
+            Lisp(e) => write!(f, "Lisp compilation failed:\n    {}", e),
+        }
+    }
+}
+
+impl Error for CompileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        use self::CompileError::*;
+        match self {
+            Asm(errors) => errors.first().map(|e| e as &(dyn Error + 'static)),
+            UnmatchedBrackets(_) => None,
+            Lisp(e) => Some(e),
         }
     }
 }
@@ -99,13 +278,26 @@ impl Error for VmError {
 pub enum VmState {
     Continue,
     Halt,
+    /// The step budget given to `run_with_budget` ran out before the program halted
+    OutOfFuel,
 }
 
-impl From<tape::TapeError> for VmError {
-    fn from(e: tape::TapeError) -> VmError {
+impl VmError {
+    /// Bytecode offset of the instruction which caused the error, if known
+    pub fn location(&self) -> Option<usize> {
+        match self {
+            VmError::InvalidOperation(_, location) | VmError::TapeError(_, location) => {
+                Some(*location)
+            }
+            _ => None,
+        }
+    }
+
+    // Attach the location of the failing operation to a tape error
+    fn from_tape_error(e: tape::TapeError, location: usize) -> VmError {
         match e {
             tape::TapeError::Eof => VmError::UnexpectedEof,
-            _ => VmError::TapeError(e),
+            _ => VmError::TapeError(e, location),
         }
     }
 }
@@ -128,6 +320,7 @@ impl Program {
             lang,
             sourcecode: sourcecode.to_string(),
             bytecode: Tape::new(vec![]),
+            source_map: SourceMap::new(),
         }
     }
 
@@ -143,358 +336,418 @@ impl Program {
         Program::new(lang, &sourcecode)
     }
 
-    fn compile(&mut self) {
+    pub fn compile(&mut self, opt_level: OptLevel) -> Result<(), CompileError> {
+        self.bytecode = Tape::new(vec![]);
+        self.source_map.clear();
         match self.lang {
-            Lang::Bf => self.compile_bf(),
-            Lang::Lisp => self.compile_lisp(),
-            _ => unimplemented!(),
+            Lang::Raw => self.compile_raw()?,
+            Lang::Bf => self.compile_bf(opt_level)?,
+            Lang::Lisp => self.compile_lisp(opt_level)?,
         }
+        Ok(())
     }
 
     pub fn debug_inject_byte(&mut self, b: u8) {
         self.bytecode.push(b);
     }
 
-    fn compile_lisp(&mut self) {
-        let tokens = lisp::tokenize(&self.sourcecode);
-        let _ast = lisp::parse(tokens);
+    /// Check the compiled bytecode for malformed instructions and bad jumps
+    pub fn verify(&self) -> Result<(), Vec<VerifyError>> {
+        verify::verify(&self.bytecode)
+    }
+
+    /// Produce assembly for the compiled bytecode, which assembles back to the same bytes.
+    /// Instructions are commented with the source they came from, if known.
+    pub fn disassemble(&self) -> String {
+        if self.source_map.is_empty() {
+            return asm::disassemble(&self.bytecode);
+        }
+        asm::disassemble_annotated(&self.bytecode, |offset| {
+            self.source_map.lookup(offset).map(|span| span.to_string())
+        })
+    }
+
+    /// Translate the compiled bytecode to a standalone C program. Reading past the end of
+    /// input behaves like `eof_policy`.
+    pub fn to_c(&self, eof_policy: EofPolicy) -> Result<String, Vec<VerifyError>> {
+        let decoded = decode::decode(&self.bytecode)?;
+        Ok(backend::c::emit(&decoded, eof_policy))
+    }
+
+    /// Translate the compiled bytecode to a Rust module with a `run` function which behaves
+    /// like the VM. Reading past the end of input behaves like `eof_policy`.
+    pub fn to_rust(&self, eof_policy: EofPolicy) -> Result<String, Vec<VerifyError>> {
+        let decoded = decode::decode(&self.bytecode)?;
+        Ok(backend::rust::emit(&decoded, eof_policy))
+    }
+
+    /// Translate the compiled bytecode to a WebAssembly text module, with the tape in its
+    /// memory. Reading past the end of input behaves like `eof_policy`.
+    pub fn to_wat(&self, eof_policy: EofPolicy) -> Result<String, Vec<VerifyError>> {
+        let decoded = decode::decode(&self.bytecode)?;
+        Ok(backend::wat::emit(&decoded, eof_policy))
+    }
+
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    /// Drop the debug information, so that saving only keeps the bytecode
+    pub fn strip(&mut self) {
+        self.sourcecode.clear();
+        self.source_map.clear();
+    }
+
+    /// Encode the compiled program in the object file format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        object::Object {
+            lang: self.lang,
+            bytecode: self.bytecode.iter().cloned().collect(),
+            sourcecode: if self.sourcecode.is_empty() {
+                None
+            } else {
+                Some(self.sourcecode.clone())
+            },
+            source_map: if self.source_map.is_empty() {
+                None
+            } else {
+                Some(self.source_map.clone())
+            },
+        }
+        .to_bytes()
+    }
+
+    /// Decode a compiled program from the object file format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, ObjectError> {
+        let object = object::Object::from_bytes(bytes)?;
+        Ok(Program {
+            lang: object.lang,
+            sourcecode: object.sourcecode.unwrap_or_default(),
+            bytecode: Tape::new(object.bytecode),
+            source_map: object.source_map.unwrap_or_default(),
+        })
+    }
+
+    /// Write the compiled program to an object file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ObjectError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Read a compiled program from an object file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Program, ObjectError> {
+        Program::from_bytes(&fs::read(path)?)
+    }
+
+    fn compile_raw(&mut self) -> Result<(), CompileError> {
+        let (bytecode, source_map) = asm::assemble(&self.sourcecode).map_err(CompileError::Asm)?;
+        self.bytecode = bytecode;
+        self.source_map = source_map;
+        Ok(())
+    }
+
+    fn compile_lisp(&mut self, opt_level: OptLevel) -> Result<(), CompileError> {
+        let tokens = lisp::tokenize(&self.sourcecode).map_err(CompileError::Lisp)?;
+        let ast = lisp::parse(tokens).map_err(CompileError::Lisp)?;
+        self.bytecode = lisp::compile(&ast, opt_level).map_err(CompileError::Lisp)?;
+        Ok(())
     }
 
     // BF specific stuff
 
-    fn compile_bf(&mut self) {
+    fn compile_bf(&mut self, opt_level: OptLevel) -> Result<(), CompileError> {
         match self.lang {
             Lang::Bf => (),
             _ => panic!("tried to compile wrong language"),
         }
 
-        //println!("--------");
-        //println!("Compiling bytecode from BF");
-        //println!();
-
-        use std::collections::HashMap;
-        use Opcode::*;
-
-        let mut ops = HashMap::new();
-        ops.insert('+', Inc);
-        ops.insert('-', Dec);
-        ops.insert('>', IncTape);
-        ops.insert('<', DecTape);
-        ops.insert('.', OutputByte);
-        ops.insert(',', InputByte);
-        ops.insert('[', StartLoop);
-        ops.insert(']', EndLoop);
-
-        let mut tmp = Tape::new(vec![]);
-
-        let mut size = 0;
-        for c in self.sourcecode.chars() {
-            if let Some(&com) = ops.get(&c) {
-                tmp.push(com);
-                size = size + 1;
-            }
-        }
-        //println!("souce code size : {} byte(s)", size);
-
-        // This way the run loop can start with inc_read even
-        // tho the index is a usize (so no negative)
-        // this is a hack
-        self.bytecode.push(0);
-
-        let mut loop_stack = vec![];
-        let mut count: isize;
-        let mut next_command;
-        let mut index = 0;
-        while index < tmp.len() {
-            let current = tmp[index];
-            match current {
-                Inc | Dec => {
-                    if current == Inc {
-                        count = 1;
-                    } else {
-                        count = -1;
-                    }
-                    while index + 1 < tmp.len() {
-                        next_command = tmp[index + 1];
-                        match next_command {
-                            Inc => {
-                                if count.abs() >= std::i8::MAX as isize {
-                                    break;
-                                }
-                                count += 1;
-                            }
-                            Dec => {
-                                if count.abs() >= std::i8::MAX as isize {
-                                    break;
-                                }
-                                count -= 1;
-                            }
-                            _ => break,
-                        }
-                        index = index + 1;
-                    }
-                    if count == 1 {
-                        self.bytecode.push(Inc.into());
-                    } else if count == -1 {
-                        self.bytecode.push(Dec.into());
-                    } else {
-                        self.bytecode.push(SubImmediate.into());
-                        self.bytecode.push(-count as i8 as u8);
-                    }
-                }
-                IncTape | DecTape => {
-                    if current == IncTape {
-                        count = 1;
-                    } else {
-                        count = -1;
-                    }
-                    while index + 1 < tmp.len() {
-                        next_command = tmp[index + 1];
-                        match next_command {
-                            IncTape => {
-                                if count.abs() >= std::i8::MAX as isize {
-                                    break;
-                                }
-                                count += 1;
-                            }
-                            DecTape => {
-                                if count.abs() >= std::i8::MAX as isize {
-                                    break;
-                                }
-                                count -= 1;
-                            }
-                            _ => break,
-                        }
-                        index = index + 1;
-                    }
-                    if count == 1 {
-                        self.bytecode.push(IncTape.into());
-                    } else if count == -1 {
-                        self.bytecode.push(DecTape.into());
-                    } else if count.abs() <= 127 {
-                        self.bytecode.push(MoveTapeShort.into());
-                        self.bytecode.push(count as i8 as u8);
-                    } else {
-                        self.bytecode.push(MoveTapeLong.into());
-                        self.bytecode.push_int(2, count as i16 as u32);
-                    }
-                }
-                OutputByte | InputByte => self.bytecode.push(current as i8 as u8),
-                StartLoop => {
-                    self.bytecode.push(JumpAbsoluteIfZero.into());
-                    for _i in 0..4 {
-                        self.bytecode.push(0);
-                    }
-                    loop_stack.push(self.bytecode.len());
-                }
-                EndLoop => {
-                    // TODO: line number for this error?
-                    let target = loop_stack
-                        .pop()
-                        .expect("unmatched bracket while compiling BF");
-                    self.bytecode.push(JumpAbsoluteIfNonzero.into());
-                    self.bytecode.push_int(4, target as u32);
-                    let here = self.bytecode.len();
-                    self.bytecode.write_int_at(target - 4, 4, here as u32)
-                }
-                _ => unreachable!(), // in theory
-            };
-            index += 1;
-        }
-
-        self.bytecode.push(HaltAlways.into());
-
-        /*
-        let mut count = 0;
-        let mut prev_com = None;
-        for c in self.sourcecode.chars() {
-            if let Some(&com) = ops.get(&c) {
-                match com {
-                    Inc | Dec | IncTape | DecTape => {
-                        if let Some(inside) = prev_com {
-                            if com == inside {
-                                count += 1;
-                            } else if count > 1 {
-                                match inside {
-                                    Inc | Dec | IncTape | DecTape => {
-                                        // end of a run
-                                        println!("{:?} run of {}", inside, count);
-                                        count = 1;
-                                    }
-                                    _ => count = 0,
-                                }
-                            }
-                        } else {
-                            // first command
-                            println!("    First: {:?}", com);
-                            count += 1;
-                        }
-                    }
-                    OutputByte | InputByte | StartLoop | EndLoop => println!("{:?}, ", com),
-                    _ => unreachable!(),
-                }
-                //self.bytecode.push(com as i8);
-                prev_com = Some(com);
-                println!("{}", count);
-            }
-        }
-        //self.bytecode.push(HaltAlways);
-        println!("\n");
-        */
-
-        //println!("compiled size : {} byte(s)", self.bytecode.len());
+        let (bytecode, source_map) =
+            bf::compile(&self.sourcecode, opt_level).map_err(CompileError::UnmatchedBrackets)?;
+        self.bytecode = bytecode;
+        self.source_map = source_map;
+        Ok(())
+    }
+}
 
-        //println!("Finished");
-        //println!();
+impl Default for STVM {
+    fn default() -> STVM {
+        STVM::new()
     }
 }
 
 impl STVM {
+    /// Create a VM reading from stdin and writing to stdout
     pub fn new() -> STVM {
+        STVM::with_io(io::stdin(), io::stdout())
+    }
+
+    /// Create a VM which reads `InputByte` from `input` and writes `OutputByte` to `output`
+    pub fn with_io<R, W>(input: R, output: W) -> STVM
+    where
+        R: Read + 'static,
+        W: Write + 'static,
+    {
         STVM {
             program: Program {
                 lang: Lang::Raw,
                 sourcecode: String::from(""),
                 bytecode: Tape::new(vec![]),
+                source_map: SourceMap::new(),
             },
             tape: Tape::new(vec![0]),
             stack: Tape::new(vec![0]),
             registers: RegisterSet::new(),
+            input: Box::new(input),
+            output: Box::new(output),
+            eof_policy: EofPolicy::default(),
+            cancel: CancelToken::new(),
             prng: Prng::new_from_time(),
+            decoded: None,
+            pc: 0,
+            dispatched: 0,
+            #[cfg(feature = "jit")]
+            jit: None,
         }
     }
 
+    /// Replace the streams used by `InputByte` and `OutputByte`
+    pub fn set_io<R, W>(&mut self, input: R, output: W)
+    where
+        R: Read + 'static,
+        W: Write + 'static,
+    {
+        self.input = Box::new(input);
+        self.output = Box::new(output);
+    }
+
+    /// Choose how `InputByte` behaves at the end of input
+    pub fn set_eof_policy(&mut self, policy: EofPolicy) {
+        self.eof_policy = policy;
+    }
+
+    /// Get a token which interrupts this VM's `run` when cancelled
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
     fn set_program(&mut self, program: Program) {
         self.program = program;
+        self.decoded = None;
+        #[cfg(feature = "jit")]
+        {
+            self.jit = None;
+        }
     }
 
-    pub fn from_code(lang: Lang, sourcecode: &str) -> STVM {
+    /// Create a VM for an already compiled program, such as one loaded from an object file
+    pub fn from_program(program: Program) -> STVM {
         let mut vm = STVM::new();
-        vm.set_program(Program::new(lang, sourcecode));
-        vm.compile();
+        vm.set_program(program);
         vm
     }
 
-    pub fn from_file(lang: Lang, filename: &str) -> STVM {
+    pub fn from_code(lang: Lang, sourcecode: &str) -> Result<STVM, CompileError> {
+        let mut vm = STVM::new();
+        vm.set_program(Program::new(lang, sourcecode));
+        vm.compile(OptLevel::default())?;
+        Ok(vm)
+    }
+
+    pub fn from_file(lang: Lang, filename: &str) -> Result<STVM, CompileError> {
         let mut vm = STVM::new();
         vm.set_program(Program::from_file(lang, filename));
-        vm.compile();
-        vm
+        vm.compile(OptLevel::default())?;
+        Ok(vm)
     }
 
-    pub fn compile(&mut self) {
-        self.program.compile()
+    pub fn compile(&mut self, opt_level: OptLevel) -> Result<(), CompileError> {
+        self.program.compile(opt_level)
     }
 
-    pub fn step(&mut self) -> Result<VmState, VmError> {
-        use Opcode::*;
+    pub fn verify(&self) -> Result<(), Vec<VerifyError>> {
+        self.program.verify()
+    }
 
-        //let index = self.program.bytecode.get_cursor();
+    pub fn disassemble(&self) -> String {
+        self.program.disassemble()
+    }
 
-        //self.program.bytecode.inc_cursor();
-        //let op = self.program.bytecode.peek();
+    /// Decode the whole program once and run that from now on, rather than decoding each
+    /// instruction every time it is executed. The bytecode has to pass verification.
+    pub fn decode(&mut self) -> Result<(), Vec<VerifyError>> {
+        let decoded = decode::decode(&self.program.bytecode)?;
+        let cursor = self.program.bytecode.get_cursor();
+        self.pc = decoded
+            .index_of(cursor)
+            .unwrap_or(decoded.instructions.len());
+        self.decoded = Some(decoded);
+        Ok(())
+    }
 
-        let op = self.program.bytecode.peek();
-        let com = Opcode::from_u8(op).ok_or(VmError::InvalidOperation(
-            op,
-            self.program.bytecode.get_cursor(),
-        ))?;
+    /// Decode the program and generate machine code for it, which `run` then uses. Returns
+    /// whether machine code could be generated for this machine; if not, the decoded program
+    /// is interpreted as usual. Running with a budget always interprets.
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) -> Result<bool, Vec<VerifyError>> {
+        if self.decoded.is_none() {
+            self.decode()?;
+        }
+        self.jit = jit::JitCode::compile(self.decoded.as_ref().unwrap());
+        Ok(self.jit.is_some())
+    }
 
-        self.program.bytecode.inc_cursor();
+    /// Find the source code of the instruction at a bytecode offset, e.g. from `VmError::location`
+    pub fn source_span(&self, offset: usize) -> Option<SourceSpan> {
+        self.program.source_map.lookup(offset)
+    }
 
-        //println!("   {:08x}: {:?} ({}, {:02x})", index, com, op, op);
+    pub fn sourcecode(&self) -> &str {
+        &self.program.sourcecode
+    }
 
-        //let index = self.program.bytecode.get_cursor();
-        //println!("{:08x}: {:?} ({}, {:02x})", index, com, op, op);
+    /// Number of instructions executed so far, counting a superinstruction as one
+    pub fn dispatch_count(&self) -> u64 {
+        self.dispatched
+    }
 
-        /*
-        let com;
-        if let Some(com0) = Opcode::from_i8(self.program.bytecode.peek()) {
-            com = com0;
-        } else {
-            return Err(VmError::Other);VmError::Other
+    pub fn step(&mut self) -> Result<VmState, VmError> {
+        self.dispatched += 1;
+        if self.decoded.is_none() {
+            return self.step_bytes();
+        }
+        let result = self.step_decoded();
+        if !matches!(result, Ok(VmState::Continue)) {
+            // leave the bytecode cursor where it would be without decoding, for debugging
+            let decoded = self.decoded.as_ref().unwrap();
+            let location = decoded.offsets.get(self.pc).cloned();
+            self.program
+                .bytecode
+                .jump(location.unwrap_or(self.program.bytecode.len()))
+                .ok();
         }
-        // */
+        result
+    }
 
-        match com {
-            Nop => (),
-            Inc => self.registers.arithmetic_overflow = self.tape.i8_subtract(-1),
-            Dec => self.registers.arithmetic_overflow = self.tape.i8_subtract(1),
-            IncTape => self.registers.tape_outside_right_bound = self.tape.move_cursor(1),
-            DecTape => self.registers.tape_outside_right_bound = self.tape.move_cursor(-1),
-            Set => {
-                let (n, _) = self.program.bytecode.read_inc();
-                self.tape.write(n);
+    // Decode and execute the instruction at the bytecode cursor
+    fn step_bytes(&mut self) -> Result<VmState, VmError> {
+        let location = self.program.bytecode.get_cursor();
+        let (instruction, length) = decode::decode_at(&self.program.bytecode, location)?;
+        self.program.bytecode.skip(length);
+
+        match self.execute(instruction, location)? {
+            Flow::Continue => Ok(VmState::Continue),
+            Flow::Jump(target) => {
+                self.program
+                    .bytecode
+                    .jump(target)
+                    .map_err(|e| VmError::from_tape_error(e, location))?;
+                Ok(VmState::Continue)
             }
-            SubImmediate => {
-                let (n, _) = self.program.bytecode.read_inc();
-                self.registers.arithmetic_overflow = self.tape.i8_subtract(n as i8);
+            Flow::Halt => Ok(VmState::Halt),
+        }
+    }
+
+    // Execute the next instruction of the decoded program
+    fn step_decoded(&mut self) -> Result<VmState, VmError> {
+        let (instruction, location) = {
+            let decoded = self.decoded.as_ref().expect("program was not decoded");
+            match decoded.instructions.get(self.pc) {
+                Some(&instruction) => (instruction, decoded.offsets[self.pc]),
+                None => return Err(VmError::UnexpectedEof),
             }
-            SubRelativeLong => {
-                let n = self.program.bytecode.read_int(2)?;
-                let m = self.tape.peek_relative(n as i32 as isize);
-                self.registers.arithmetic_overflow = self.tape.i8_subtract(m as i8);
+        };
+        self.pc += 1;
+
+        match self.execute(instruction, location)? {
+            Flow::Continue => Ok(VmState::Continue),
+            Flow::Jump(target) => {
+                self.pc = target;
+                Ok(VmState::Continue)
             }
-            MoveTapeShort => {
-                let n = self.program.bytecode.read_int(1)?;
-                self.registers.tape_outside_right_bound = self.tape.move_cursor(n as i8 as isize)
+            Flow::Halt => Ok(VmState::Halt),
+        }
+    }
+
+    // Carry out an instruction, `location` being its offset in the bytecode
+    fn execute(&mut self, instruction: Instruction, location: usize) -> Result<Flow, VmError> {
+        use decode::Instruction::*;
+
+        let tape_error = |e| VmError::from_tape_error(e, location);
+
+        match instruction {
+            Nop => (),
+            Sub(n) => self.registers.arithmetic_overflow = self.tape.i8_subtract(n as i8),
+            SubRelative(offset) => {
+                let m = self.tape.peek_relative(offset).map_err(tape_error)?;
+                self.registers.arithmetic_overflow = self.tape.i8_subtract(m as i8);
             }
-            MoveTapeLong => {
-                let n = self.program.bytecode.read_int(2)?;
-                self.registers.tape_outside_right_bound = self.tape.move_cursor(n as i16 as isize)
+            SubImmediateRelative(offset, n) => {
+                self.registers.arithmetic_overflow = self
+                    .tape
+                    .i8_subtract_relative(offset, n as i8)
+                    .map_err(tape_error)?;
             }
-            SeekRight => {
-                while self.tape.peek() != 0 {
-                    // TODO: Optimize?
-                    self.tape.move_cursor(1);
+            Set(n) => self.tape.write(n),
+            SetRelative(offset, n) => self.tape.write_relative(offset, n).map_err(tape_error)?,
+            MulSubRelative(offset, factor) => {
+                let m = self.tape.peek();
+                if m != 0 {
+                    self.registers.arithmetic_overflow = self
+                        .tape
+                        .i8_subtract_relative(offset, m.wrapping_mul(factor) as i8)
+                        .map_err(tape_error)?;
                 }
             }
-            SeekLeft => {
-                while self.tape.peek() != 0 {
-                    // TODO: Optimize?
-                    self.tape.move_cursor(-1);
-                }
+            MoveTape(n) => {
+                self.registers.tape_outside_right_bound =
+                    self.tape.move_cursor(n).map_err(tape_error)?
             }
-            JumpRelativeShortIfZero => {
-                let target = self.program.bytecode.read_int(1)?;
-                self.program.bytecode.jump_relative(target as isize);
+            SubMoveTape(n, m) => {
+                self.registers.arithmetic_overflow = self.tape.i8_subtract(n as i8);
+                self.registers.tape_outside_right_bound =
+                    self.tape.move_cursor(m).map_err(tape_error)?
             }
-            /*JumpRelativeShortIfNonzero(target) => if self.tape.peek() != 0 {
-                self.program.bytecode.jump_relative(target as isize);
+            MoveTapeJumpIfNonzero(n, target) => {
+                self.registers.tape_outside_right_bound =
+                    self.tape.move_cursor(n).map_err(tape_error)?;
+                if self.tape.peek() != 0 {
+                    return Ok(Flow::Jump(target));
+                }
             }
-            JumpRelativeLongIfZero(target) => if self.tape.peek() == 0 {
-                self.program.bytecode.jump_relative(target as isize);
+            Seek(stride) => {
+                self.registers.tape_outside_right_bound = self
+                    .tape
+                    .seek_zero(stride.unsigned_abs(), stride > 0)
+                    .map_err(tape_error)?
             }
-            JumpRelativeLongIfNonzero(target) => if self.tape.peek() != 0 {
-                self.program.bytecode.jump_relative(target as isize);
-            }*/
-            JumpAbsoluteIfZero => {
-                let target = self.program.bytecode.read_int(4)?;
+            JumpIfZero(target) => {
                 if self.tape.peek() == 0 {
-                    //println!("{}", target as usize - 1);
-                    self.program.bytecode.jump(target as usize);
+                    return Ok(Flow::Jump(target));
                 }
             }
-            JumpAbsoluteIfNonzero => {
-                let target = self.program.bytecode.read_int(4)?;
+            JumpIfNonzero(target) => {
                 if self.tape.peek() != 0 {
-                    //println!("{}, {}, {}", target, self.program.bytecode.len(), target as usize - 1);
-                    self.program.bytecode.jump(target as usize);
+                    return Ok(Flow::Jump(target));
                 }
             }
             InputByte => {
                 // TODO: fix it so it takes input
                 // immediately instead of waiting for line end
                 let mut buffer = [0u8; 1];
-                let mut stdin = io::stdin();
-                stdin.lock();
-                match stdin.read(&mut buffer) {
-                    Err(e) => panic!("{:?}", e),
+                match self.input.read(&mut buffer) {
+                    Err(e) => return Err(VmError::Stream(e)),
                     Ok(n) => {
                         if n == 1 {
                             self.tape.write(buffer[0]);
                         } else if n == 0 {
-                            return Err(VmError::Io("no bytes read from input"));
+                            match self.eof_policy {
+                                EofPolicy::Unchanged => (),
+                                EofPolicy::Zero => self.tape.write(0),
+                                EofPolicy::MinusOne => self.tape.write(0xff),
+                                EofPolicy::Error => {
+                                    return Err(VmError::Io("no bytes read from input"))
+                                }
+                            }
                         } else {
                             //return Err(&format!("wrong number of bytes read! {} bytes", n));
                             return Err(VmError::Io("wrong number of bytes read!"));
@@ -503,17 +756,19 @@ impl STVM {
                 }
             }
             OutputByte => {
-                print!("{}", self.tape.peek() as u8 as char);
-                io::stdout().flush().unwrap();
+                let byte = [self.tape.peek()];
+                self.output
+                    .write_all(&byte)
+                    .and_then(|_| self.output.flush())
+                    .map_err(VmError::Stream)?;
             }
             //OutputDebug => {
             //println!("{}", self.tape.peek());
             //io::stdout().flush().unwrap();
             //}
-            HaltIfNotEqual => {
-                let (n, _) = self.program.bytecode.read_inc();
+            HaltIfNotEqual(n) => {
                 if self.tape.peek() != n {
-                    return Ok(VmState::Halt);
+                    return Ok(Flow::Halt);
                 }
             }
             Push => self.stack.push(self.tape.peek()),
@@ -529,23 +784,58 @@ impl STVM {
                 //self.stack.push((r >> 8) as i8);
                 //self.stack.push((r & 0xff) as i8);
             }
-            HaltAlways => return Ok(VmState::Halt),
-            _ => {
-                return Err(VmError::UnexpectedCommand(com));
-            }
+            HaltAlways => return Ok(Flow::Halt),
         }
-        //Err(VmError::Halt)
-        Ok(VmState::Continue)
+        Ok(Flow::Continue)
     }
 
     pub fn run(&mut self) -> Result<(), VmError> {
+        self.run_until(None).map(|_| ())
+    }
+
+    /// Run until the program halts or `budget` opcodes have been executed,
+    /// returning `VmState::Halt` or `VmState::OutOfFuel` respectively
+    pub fn run_with_budget(&mut self, budget: u64) -> Result<VmState, VmError> {
+        self.run_until(Some(budget))
+    }
+
+    fn run_until(&mut self, budget: Option<u64>) -> Result<VmState, VmError> {
+        #[cfg(feature = "jit")]
+        {
+            if budget.is_none() && self.jit.is_some() {
+                return self.run_jit();
+            }
+        }
+
+        let mut steps: u64 = 0;
+        loop {
+            if budget.is_some_and(|b| steps >= b) {
+                return Ok(VmState::OutOfFuel);
+            }
+            if steps.is_multiple_of(CANCEL_CHECK_INTERVAL) && self.cancel.is_cancelled() {
+                self.cancel.reset();
+                return Err(VmError::Interrupted);
+            }
+            match self.step()? {
+                VmState::Continue => steps += 1,
+                s => return Ok(s),
+            }
+        }
+    }
+
+    // Run machine code until it stops at an instruction it can not do, interpret that, repeat
+    #[cfg(feature = "jit")]
+    fn run_jit(&mut self) -> Result<VmState, VmError> {
         loop {
-            match self.step() {
-                Ok(s) => match s {
-                    VmState::Continue => continue,
-                    VmState::Halt => return Ok(()),
-                },
-                Err(e) => return Err(e),
+            if self.cancel.is_cancelled() {
+                self.cancel.reset();
+                return Err(VmError::Interrupted);
+            }
+            let code = self.jit.as_ref().expect("no machine code");
+            self.pc = code.run(&mut self.tape, self.pc, &self.cancel.0);
+            match self.step()? {
+                VmState::Continue => (),
+                s => return Ok(s),
             }
         }
     }
@@ -570,6 +860,11 @@ impl STVM {
 
     pub fn debug_inject_byte(&mut self, b: u8) {
         self.program.debug_inject_byte(b);
+        self.decoded = None;
+        #[cfg(feature = "jit")]
+        {
+            self.jit = None;
+        }
     }
 
     pub fn debug_print(&self) {
@@ -582,10 +877,516 @@ impl STVM {
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    // Output sink which can still be inspected after being handed to a VM; also used by the JIT's
+    // tests
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
     #[test]
     fn compiling_test() {
-        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+++++[>+++<-]>");
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+++++[>+++<-]>").unwrap();
         test_vm.run().expect("VM error");
         assert_eq!(test_vm.tape.peek(), 15);
     }
+
+    #[test]
+    fn io_test() {
+        let output = SharedBuffer::default();
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, ",+.,+.").unwrap();
+        test_vm.set_io(&b"HI"[..], output.clone());
+        test_vm.run().expect("VM error");
+        assert_eq!(&output.0.borrow()[..], b"IJ");
+    }
+
+    #[test]
+    fn eof_policy_test() {
+        use super::EofPolicy::*;
+
+        for &(policy, expected) in &[(Unchanged, 7), (Zero, 0), (MinusOne, 255)] {
+            let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+++++++,").unwrap();
+            test_vm.set_io(io::empty(), io::sink());
+            test_vm.set_eof_policy(policy);
+            test_vm.run().expect("VM error");
+            assert_eq!(test_vm.tape.peek(), expected);
+        }
+
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, ",").unwrap();
+        test_vm.set_io(io::empty(), io::sink());
+        test_vm.set_eof_policy(Error);
+        assert!(test_vm.run().is_err());
+    }
+
+    #[test]
+    fn budget_test() {
+        use super::VmState;
+
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+[]").unwrap();
+        match test_vm.run_with_budget(1000) {
+            Ok(VmState::OutOfFuel) => (),
+            other => panic!("expected to run out of fuel, got {:?}", other),
+        }
+
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+++").unwrap();
+        match test_vm.run_with_budget(1000) {
+            Ok(VmState::Halt) => (),
+            other => panic!("expected to halt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cancel_test() {
+        use std::thread;
+        use std::time::Duration;
+
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+[]").unwrap();
+        let token = test_vm.cancel_token();
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                token.cancel();
+            })
+        };
+        match test_vm.run() {
+            Err(super::VmError::Interrupted) => (),
+            other => panic!("expected to be interrupted, got {:?}", other),
+        }
+        canceller.join().unwrap();
+
+        // the cancel was used up, so the VM runs again
+        assert!(!token.is_cancelled());
+        match test_vm.run_with_budget(100) {
+            Ok(super::VmState::OutOfFuel) => (),
+            other => panic!("expected to run out of fuel, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tape_error_test() {
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+<").unwrap();
+        match test_vm.run() {
+            Err(super::VmError::TapeError(super::TapeError::OutOfBounds, location)) => {
+                assert_eq!(location, 2)
+            }
+            other => panic!("expected a tape error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verify_compiled_test() {
+        let test_vm = super::STVM::from_code(super::Lang::Bf, "+[->>+<[-]<]>>.").unwrap();
+        assert_eq!(test_vm.verify(), Ok(()));
+    }
+
+    #[test]
+    fn raw_test() {
+        let source = "
+                    Set 3
+            loop:   Push
+                    SubImmediate 1
+                    JumpRelativeShortIfNonzero loop
+                    Pop
+                    MoveTapeShort 1
+                    Pop
+                    HaltAlways
+        ";
+        let mut test_vm = super::STVM::from_code(super::Lang::Raw, source).unwrap();
+        test_vm.run().expect("VM error");
+        assert_eq!(
+            test_vm.each_cell().cloned().collect::<Vec<u8>>(),
+            vec![1, 2]
+        );
+
+        assert!(super::STVM::from_code(super::Lang::Raw, "Bogus").is_err());
+    }
+
+    #[test]
+    fn object_test() {
+        let mut program = super::Program::new(super::Lang::Bf, "++[->+++<]>.");
+        program.compile(super::OptLevel::default()).unwrap();
+        let bytes = program.to_bytes();
+
+        let loaded = super::Program::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.sourcecode, program.sourcecode);
+        let mut test_vm = super::STVM::from_program(loaded);
+        let output = SharedBuffer::default();
+        test_vm.set_io(io::empty(), output.clone());
+        test_vm.run().expect("VM error");
+        assert_eq!(&output.0.borrow()[..], &[6]);
+
+        program.strip();
+        assert!(program.to_bytes().len() < bytes.len());
+    }
+
+    #[test]
+    fn source_map_test() {
+        use super::SourcePosition;
+
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+++\n>> .\n  <<<<").unwrap();
+        let e = test_vm.run().unwrap_err();
+        let span = test_vm.source_span(e.location().unwrap()).unwrap();
+        assert_eq!(span.start, SourcePosition { line: 3, column: 3 });
+        assert_eq!(span.end, SourcePosition { line: 3, column: 6 });
+
+        let bytes = test_vm.program.to_bytes();
+        let loaded = super::Program::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.source_map, test_vm.program.source_map);
+    }
+
+    #[test]
+    fn clear_loop_test() {
+        use super::Opcode::*;
+
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+++[-]>-[+]+").unwrap();
+        test_vm.compile(super::OptLevel::O2).unwrap();
+        assert_eq!(
+            test_vm
+                .program
+                .bytecode
+                .iter()
+                .cloned()
+                .collect::<Vec<u8>>(),
+            vec![
+                Nop.into(),
+                Set.into(),
+                0,
+                SetRelativeLong.into(),
+                0,
+                1,
+                1,
+                IncTape.into(),
+                HaltAlways.into(),
+            ]
+        );
+        test_vm.run().unwrap();
+        assert_eq!(
+            test_vm.tape.iter().cloned().collect::<Vec<u8>>(),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn scan_loop_test() {
+        use super::Opcode::*;
+
+        let mut test_vm =
+            super::STVM::from_code(super::Lang::Bf, ">+>+>+>>+<<[<]>>>>>[>>]+[<<<]").unwrap();
+        test_vm.compile(super::OptLevel::O2).unwrap();
+        let bytecode: Vec<u8> = test_vm.program.bytecode.iter().cloned().collect();
+        assert!(bytecode.contains(&SeekLeft.into()));
+        assert!(bytecode
+            .windows(2)
+            .any(|w| w == [SeekRightStride.into(), 2]));
+        assert!(bytecode.windows(2).any(|w| w == [SeekLeftStride.into(), 3]));
+
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.tape.get_cursor(), 4);
+        assert_eq!(
+            test_vm.tape.iter().cloned().collect::<Vec<u8>>(),
+            vec![0, 1, 1, 1, 0, 1, 0, 1]
+        );
+    }
+
+    #[test]
+    fn long_run_test() {
+        use super::{OptLevel, Program};
+
+        // merged into a single subtraction of -128
+        let source = "+".repeat(128);
+        for &opt_level in &[OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let mut program = Program::new(super::Lang::Bf, &source);
+            program.compile(opt_level).unwrap();
+            let mut test_vm = super::STVM::with_io(std::io::empty(), std::io::sink());
+            test_vm.set_program(program);
+            test_vm.run().unwrap();
+            assert_eq!(test_vm.tape.peek(), 128);
+        }
+    }
+
+    #[test]
+    fn offset_fold_test() {
+        use super::Opcode::*;
+
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, ">+>++<<->>>").unwrap();
+        test_vm.compile(super::OptLevel::O2).unwrap();
+        assert_eq!(
+            test_vm
+                .program
+                .bytecode
+                .iter()
+                .cloned()
+                .collect::<Vec<u8>>(),
+            vec![
+                Nop.into(),
+                SubImmediateRelativeLong.into(),
+                0,
+                1,
+                -1i8 as u8,
+                SubImmediateRelativeLong.into(),
+                0,
+                2,
+                -2i8 as u8,
+                Dec.into(),
+                MoveTapeShort.into(),
+                3,
+                HaltAlways.into(),
+            ]
+        );
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.tape.get_cursor(), 3);
+        assert_eq!(
+            test_vm.tape.iter().cloned().collect::<Vec<u8>>(),
+            vec![255, 1, 2, 0]
+        );
+    }
+
+    // Run BF at each optimization level and check the output and result match O0's, with errors
+    // compared by kind since their locations are in different bytecode
+    fn assert_same_as_o0(source: &str, input: &'static [u8], budget: u64) -> bool {
+        use super::{OptLevel, Program, VmError};
+
+        let run = |opt_level| {
+            let mut program = Program::new(super::Lang::Bf, source);
+            program.compile(opt_level).unwrap();
+            let output = SharedBuffer::default();
+            let mut test_vm = super::STVM::with_io(input, output.clone());
+            test_vm.set_program(program);
+            test_vm.set_eof_policy(super::EofPolicy::Zero);
+            let result = match test_vm.run_with_budget(budget) {
+                Err(VmError::TapeError(e, _)) => format!("{:?}", e),
+                other => format!("{:?}", other),
+            };
+            let output = output.0.borrow().clone();
+            (result, output)
+        };
+
+        let expected = run(OptLevel::O0);
+        // a program still running at O0 may well have finished sooner at higher levels
+        if expected.0 == "Ok(OutOfFuel)" {
+            return false;
+        }
+        for &opt_level in &[OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            assert_eq!(run(opt_level), expected, "{} at {:?}", source, opt_level);
+        }
+        true
+    }
+
+    #[test]
+    fn optimization_equivalence_test() {
+        let programs: &[(&str, &[u8])] = &[
+            (">>>>>>.[-]+<-+.--+<-[--<<<<]<+->..,.", b"abc"),
+            ("<<>>+", b""),
+            ("+[<>]", b""),
+            ("+>+[-<<>>>+<]", b""),
+            ("+>+<[->>+<<]<", b""),
+            (">,[>,]<[.<]<<[-]", b"stvm"),
+        ];
+        for &(source, input) in programs {
+            assert!(assert_same_as_o0(source, input, 1_000_000));
+        }
+
+        let mut prng = super::Prng::new_from_seed(1234);
+        let mut finished = 0;
+        for _ in 0..500 {
+            let source: String = (0..30)
+                .map(|_| match prng.gen_u8() % 8 {
+                    0 | 1 => '+',
+                    2 => '-',
+                    3 | 4 => '<',
+                    5 | 6 => '>',
+                    _ => '.',
+                })
+                .collect();
+            // a loop around part of it, so that blocks repeat
+            let source = format!("{}[{}]{}", &source[..10], &source[10..20], &source[20..]);
+            if assert_same_as_o0(&source, b"", 10_000) {
+                finished += 1;
+            }
+        }
+        assert!(finished > 100, "only {} programs finished", finished);
+    }
+
+    #[test]
+    fn multiply_loop_test() {
+        use super::Opcode::*;
+
+        let mut test_vm =
+            super::STVM::from_code(super::Lang::Bf, "+++++[->+++>+<<]>>[-<<+>>]>-[+<<->>]")
+                .unwrap();
+        test_vm.compile(super::OptLevel::O2).unwrap();
+        let bytecode: Vec<u8> = test_vm.program.bytecode.iter().cloned().collect();
+        assert!(!bytecode.contains(&JumpAbsoluteIfZero.into()));
+        assert!(bytecode
+            .windows(4)
+            .any(|w| w == [MulSubRelativeLong.into(), 0, 1, -3i8 as u8]));
+
+        test_vm.run().unwrap();
+        assert_eq!(
+            test_vm.tape.iter().cloned().collect::<Vec<u8>>(),
+            vec![5, 14, 0, 0]
+        );
+    }
+
+    #[test]
+    fn opt_level_test() {
+        use super::{OptLevel, Program};
+
+        let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let mut sizes = vec![];
+        for &opt_level in &[OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let mut program = Program::new(super::Lang::Bf, source);
+            program.compile(opt_level).unwrap();
+            sizes.push(program.bytecode.len());
+
+            let output = SharedBuffer::default();
+            let mut test_vm = super::STVM::with_io(std::io::empty(), output.clone());
+            test_vm.set_program(program);
+            test_vm.run().unwrap();
+            assert_eq!(&output.0.borrow()[..], b"Hello World!\n");
+        }
+        assert_eq!(sizes[0], source.len() + 4 * 6 + 2);
+        assert!(sizes[1] < sizes[0]);
+    }
+
+    #[test]
+    fn superinstruction_test() {
+        use super::{OptLevel, Program};
+
+        let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        for &decoded in &[false, true] {
+            let mut counts = vec![];
+            for &opt_level in &[OptLevel::O2, OptLevel::O3] {
+                let mut program = Program::new(super::Lang::Bf, source);
+                program.compile(opt_level).unwrap();
+                let fused = program
+                    .bytecode
+                    .iter()
+                    .any(|&b| b == super::Opcode::MoveTapeShortJumpAbsoluteIfNonzero.into());
+                assert_eq!(fused, opt_level == OptLevel::O3);
+
+                let output = SharedBuffer::default();
+                let mut test_vm = super::STVM::with_io(std::io::empty(), output.clone());
+                test_vm.set_program(program);
+                if decoded {
+                    test_vm.decode().unwrap();
+                }
+                test_vm.run().unwrap();
+                assert_eq!(&output.0.borrow()[..], b"Hello World!\n");
+                counts.push(test_vm.dispatch_count());
+            }
+            assert!(counts[1] < counts[0], "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn lisp_test() {
+        use super::{OptLevel, Program};
+
+        let source = concat!(
+            "(print (add (add 2 3) 5))\n",
+            "(print \"sum:\" (add 200 100) (sub 3) (sub 10 3 2))\n",
+            "(print (sub 0 128) (sub 128) (sub 200 128))",
+        );
+        for &opt_level in &[OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let mut program = Program::new(super::Lang::Lisp, source);
+            program.compile(opt_level).unwrap();
+            program.verify().unwrap();
+
+            let output = SharedBuffer::default();
+            let mut test_vm = super::STVM::with_io(std::io::empty(), output.clone());
+            test_vm.set_program(program);
+            test_vm.run().unwrap();
+            assert_eq!(&output.0.borrow()[..], b"10\nsum: 44 253 5\n128 128 72\n");
+            assert!(!test_vm.registers.stack_underflow);
+            assert!(test_vm.tape.iter().skip(1).all(|&cell| cell == 0));
+        }
+    }
+
+    #[test]
+    fn lisp_error_test() {
+        use super::{CompileError, LispErrorKind::*};
+
+        let error = |source| match super::STVM::from_code(super::Lang::Lisp, source) {
+            Err(CompileError::Lisp(e)) => (e.line_number, e.column, e.kind),
+            other => panic!("expected a Lisp error, got {:?}", other.err()),
+        };
+        assert_eq!(
+            error("(add 1\n (mul 2 3))"),
+            (2, 3, UnknownFunction("mul".into()))
+        );
+        assert_eq!(error("(add 256)"), (1, 6, NumberOutOfRange("256".into())));
+        assert_eq!(error("(add \"1\")"), (1, 6, UnexpectedString));
+        assert_eq!(error("\n\n(print x)"), (3, 8, UnboundName("x".into())));
+        assert_eq!(error("(sub)"), (1, 1, WrongArgumentCount("sub".into())));
+        assert_eq!(error("(1 2)"), (1, 1, MissingFunction));
+        assert_eq!(error("(add 1 2))"), (1, 10, UnopenedParen));
+    }
+
+    #[test]
+    fn decoded_test() {
+        let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        for &decoded in &[false, true] {
+            let output = SharedBuffer::default();
+            let mut test_vm = super::STVM::with_io(std::io::empty(), output.clone());
+            let mut program = super::Program::new(super::Lang::Bf, source);
+            program.compile(super::OptLevel::O0).unwrap();
+            test_vm.set_program(program);
+            if decoded {
+                test_vm.decode().unwrap();
+            }
+            test_vm.run().unwrap();
+            assert_eq!(&output.0.borrow()[..], b"Hello World!\n");
+        }
+
+        let mut test_vm = super::STVM::from_code(super::Lang::Bf, "+<").unwrap();
+        test_vm.decode().unwrap();
+        match test_vm.run() {
+            Err(super::VmError::TapeError(super::TapeError::OutOfBounds, 2)) => (),
+            other => panic!("expected TapeError, got {:?}", other),
+        }
+        assert_eq!(test_vm.program.bytecode.get_cursor(), 3);
+    }
+
+    #[test]
+    fn unmatched_bracket_test() {
+        use super::{BracketError, CompileError, SourcePosition};
+
+        let at = |line, column| SourcePosition { line, column };
+        match super::STVM::from_code(super::Lang::Bf, "+]\n[[-]\n ]][") {
+            Err(CompileError::UnmatchedBrackets(errors)) => assert_eq!(
+                errors,
+                vec![
+                    BracketError::Unopened(at(1, 2)),
+                    BracketError::Unopened(at(3, 3)),
+                    BracketError::Unclosed(at(3, 4)),
+                ]
+            ),
+            other => panic!("expected UnmatchedBrackets, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn disassemble_bf_test() {
+        let test_vm = super::STVM::from_code(super::Lang::Bf, "++[->+++<]>.").unwrap();
+        let text = test_vm.disassemble();
+        let raw_vm = super::STVM::from_code(super::Lang::Raw, &text).unwrap();
+        assert_eq!(
+            raw_vm.program.bytecode.iter().collect::<Vec<_>>(),
+            test_vm.program.bytecode.iter().collect::<Vec<_>>()
+        );
+    }
 }
@@ -1,10 +1,36 @@
+/*!
+ * Lisp front end
+ *
+ * Every expression evaluates to a byte, with arithmetic wrapping around at
+ * 256, and code is generated so each leaves its value on top of the VM's
+ * stack. Cell 0 of the tape is used to work on values, with the cells to the
+ * right of it as scratch space which is always left zeroed.
+ *
+ * ```text
+ * (add 1 2 3)          ; 6
+ * (sub 10 3)           ; 7, and (sub 3) is -3, or 253
+ * (print "sum:" (add 2 3))    ; sum: 5
+ * ```
+ *
+ * `print` writes its arguments, strings as they are and numbers in decimal,
+ * separated by spaces and followed by a newline, and evaluates to 0.
+ */
+
+use std::error::Error;
 use std::fmt;
 
+use super::bf;
+use super::command::Opcode;
+use super::sourcemap::SourceMap;
+use super::tape::Tape;
+use super::OptLevel;
+
 #[derive(Debug)]
 pub struct Token {
     pub kind: TokenKind,
     pub raw: String,
     pub line_number: usize,
+    pub column: usize,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -15,7 +41,6 @@ pub enum TokenKind {
     NumberLiteral,
     StringLiteral,
     Name,
-    Error,
 }
 
 #[derive(Debug)]
@@ -24,6 +49,7 @@ pub struct AstNode {
     pub raw: String,
     pub children: Option<Vec<usize>>, // index into AST nodelist
     pub line_number: usize,
+    pub column: usize,
 }
 
 #[derive(Debug)]
@@ -39,6 +65,7 @@ impl Ast {
                 raw: "".into(),
                 children: Some(vec![]),
                 line_number: 0,
+                column: 0,
             }],
         }
     }
@@ -53,7 +80,7 @@ impl fmt::Display for Ast {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::TokenKind::*;
 
-        fn format_node(nodelist: &Vec<AstNode>, index: usize, s: String) -> String {
+        fn format_node(nodelist: &[AstNode], index: usize, s: String) -> String {
             let node = &nodelist[index];
             match node.kind {
                 Name => format!("{:?}:{}, ", node.kind, node.raw),
@@ -86,44 +113,43 @@ impl fmt::Display for Ast {
 
 impl AstNode {
     pub fn push_param(&mut self, node_id: usize) {
-        match self.children {
-            Some(ref mut v) => {
-                v.push(node_id);
-                return;
-            }
-            None => (),
+        if let Some(ref mut v) = self.children {
+            v.push(node_id);
+            return;
         }
         self.children = Some(vec![node_id]);
     }
 }
 
-/*struct Parser {
-    tokenlist: Vec<Token>,
-    current_token: i64,
-}*/
-
-pub fn tokenize(source: &str) -> Vec<Token> {
+/// Split source into tokens, or find the first thing which is not one
+pub fn tokenize(source: &str) -> Result<Vec<Token>, LispError> {
     use self::TokenKind::*;
 
     let mut cursor = 0;
     let mut tokens = Vec::new();
     let bytevec = source.as_bytes();
     let mut chr;
-    let mut has_error = false;
     let mut line_number = 1;
+    // columns are counted in characters, as in the other front ends, up to this offset so far
+    let mut counted = 0;
+    let mut counted_column = 1;
 
     while cursor < bytevec.len() {
         chr = bytevec[cursor];
+        let column = counted_column + source[counted..cursor].chars().count();
+        counted = cursor;
+        counted_column = column;
         match chr {
             b'(' | b')' => tokens.push(Token {
                 kind: Paren,
                 raw: (chr as char).to_string(),
                 line_number,
+                column,
             }),
             b'0'..=b'9' => {
                 let mut raw = "".to_string();
 
-                while chr >= b'0' && chr <= b'9' {
+                while chr.is_ascii_digit() {
                     raw.push(chr as char);
                     cursor += 1;
                     if cursor >= bytevec.len() {
@@ -136,13 +162,14 @@ pub fn tokenize(source: &str) -> Vec<Token> {
                     kind: NumberLiteral,
                     raw,
                     line_number,
+                    column,
                 });
                 cursor -= 1;
             }
             b'a'..=b'z' => {
                 let mut raw = "".to_string();
 
-                while chr >= b'a' && chr <= b'z' {
+                while chr.is_ascii_lowercase() {
                     raw.push(chr as char);
                     cursor += 1;
                     if cursor >= bytevec.len() {
@@ -155,152 +182,453 @@ pub fn tokenize(source: &str) -> Vec<Token> {
                     kind: Name,
                     raw,
                     line_number,
+                    column,
                 });
                 cursor -= 1;
             }
             b'"' => {
-                let mut raw = "".to_string();
-
-                cursor += 1;
-                if cursor >= bytevec.len() {
-                    break;
-                }
-                chr = bytevec[cursor];
-
-                while chr != b'"' {
-                    raw.push(chr as char);
-                    cursor += 1;
-                    if cursor >= bytevec.len() {
-                        break;
+                let (start_line, start_column) = (line_number, column);
+                let end = match source[cursor + 1..].find('"') {
+                    Some(i) => cursor + 1 + i,
+                    None => {
+                        return Err(LispError {
+                            line_number: start_line,
+                            column: start_column,
+                            kind: LispErrorKind::UnterminatedString,
+                        })
+                    }
+                };
+                let raw = &source[cursor + 1..end];
+                // strings may run over several lines
+                for (i, b) in raw.bytes().enumerate() {
+                    if b == b'\n' {
+                        line_number += 1;
+                        counted = cursor + 1 + i + 1;
+                        counted_column = 1;
                     }
-                    chr = bytevec[cursor];
                 }
 
                 tokens.push(Token {
                     kind: StringLiteral,
-                    raw,
-                    line_number,
+                    raw: raw.to_string(),
+                    line_number: start_line,
+                    column: start_column,
                 });
-                // not doing cursor -= 1;
-                // this way we skip the ending double-quote '"'
+                // skip the ending double-quote '"'
+                cursor = end;
             }
             b' ' | b'\t' | b'\r' => (),
-            b'\n' => line_number += 1,
+            b'\n' => {
+                line_number += 1;
+                counted = cursor + 1;
+                counted_column = 1;
+            }
             _ => {
-                tokens.push(Token {
-                    kind: Error,
-                    raw: (chr as char).to_string(),
+                let c = source[cursor..].chars().next().unwrap_or('\u{fffd}');
+                return Err(LispError {
                     line_number,
+                    column,
+                    kind: LispErrorKind::UnexpectedCharacter(c),
                 });
-                has_error = true;
-                //break; // Stop parsing tokens at the first error
             }
         }
         cursor += 1;
     }
 
-    //println!("{:?}", tokens);
-
-    if has_error {
-        println!("E: Syntax error during lexing")
-    }
-
-    for v in tokens.iter() {
-        println!("{:?}", v);
-    }
-
-    println!("\n");
-
-    tokens
+    Ok(tokens)
 }
 
-pub fn parse(tokens: Vec<Token>) -> Ast {
+/// Build the syntax tree of a program from its tokens
+pub fn parse(tokens: Vec<Token>) -> Result<Ast, LispError> {
     use self::TokenKind::*;
 
     let mut ast = Ast::new();
 
-    let mut current_token = 0;
-
+    // the calls being parsed, innermost last, under the root node
     let mut stack = vec![0];
 
-    println!("{}", ast);
-    println!();
-
-    fn add_node(nodelist: &mut Vec<AstNode>, node: AstNode, stack: &Vec<usize>) -> usize {
-        use self::TokenKind::*;
+    fn add_node(nodelist: &mut Vec<AstNode>, node: AstNode, stack: &[usize]) -> usize {
         nodelist.push(node);
         let node_id = nodelist.len() - 1;
         let &prev_id = stack.last().unwrap();
-        let parent_node = &mut nodelist[prev_id];
-        match parent_node.kind {
-            Root | CallExpression => {
-                parent_node.push_param(node_id);
-            }
-            _ => panic!(),
-        }
+        nodelist[prev_id].push_param(node_id);
         node_id
     }
 
-    while current_token < tokens.len() {
-        let token = &tokens[current_token];
-        println!("{:?}   stack: {:?}", token, stack);
+    for token in tokens {
+        let error = |kind| LispError {
+            line_number: token.line_number,
+            column: token.column,
+            kind,
+        };
 
         match token.kind {
-            Error => panic!(),
             NumberLiteral | StringLiteral | Name => {
                 add_node(
                     &mut ast.nodelist,
                     AstNode {
                         kind: token.kind,
-                        raw: token.raw.clone(),
+                        raw: token.raw,
                         children: None,
                         line_number: token.line_number,
+                        column: token.column,
                     },
                     &stack,
                 );
             }
-            Paren => {
-                match token.raw.as_ref() {
-                    "(" => {
-                        let node_id = add_node(
-                            &mut ast.nodelist,
-                            AstNode {
-                                kind: CallExpression,
-                                raw: String::new(),
-                                children: None,
-                                line_number: token.line_number,
-                            },
-                            &stack,
-                        );
-                        stack.push(node_id);
-                    }
-                    ")" => match stack.pop() {
-                        None => panic!(),
-                        _ => (), //current_token += 1,
+            Paren if token.raw == "(" => {
+                let node_id = add_node(
+                    &mut ast.nodelist,
+                    AstNode {
+                        kind: CallExpression,
+                        raw: String::new(),
+                        children: None,
+                        line_number: token.line_number,
+                        column: token.column,
                     },
-                    _ => panic!(),
+                    &stack,
+                );
+                stack.push(node_id);
+            }
+            Paren => {
+                // the root is never closed
+                if stack.len() == 1 {
+                    return Err(error(LispErrorKind::UnopenedParen));
                 }
+                stack.pop();
+            }
+            Root | CallExpression => {
+                unreachable!("the tokenizer does not produce {:?}", token.kind)
             }
-            _ => panic!(),
         }
+    }
 
-        current_token += 1;
+    // the innermost call left open
+    if stack.len() > 1 {
+        let node = &ast.nodelist[*stack.last().unwrap()];
+        return Err(LispError {
+            line_number: node.line_number,
+            column: node.column,
+            kind: LispErrorKind::UnclosedParen,
+        });
     }
 
-    println!();
-    println!();
-    println!("{}", ast);
-    println!();
+    Ok(ast)
+}
 
-    {
-        let mut i = 0;
-        for v in ast.nodelist.iter() {
-            println!("{}: {:?}", i, v);
-            i += 1;
+#[derive(Debug, Clone, PartialEq)]
+pub enum LispErrorKind {
+    // Something which cannot start a token
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    // `)` with no `(` before it
+    UnopenedParen,
+    // `(` which is never closed
+    UnclosedParen,
+    UnknownFunction(String),
+    // A name used as a value; there are no variables yet
+    UnboundName(String),
+    // A string anywhere other than as an argument of `print`
+    UnexpectedString,
+    NumberOutOfRange(String),
+    // `()`, or a call of something which is not a name
+    MissingFunction,
+    WrongArgumentCount(String),
+}
+
+/// An error in Lisp source, and where it was found
+#[derive(Debug, Clone, PartialEq)]
+pub struct LispError {
+    pub line_number: usize,
+    pub column: usize,
+    pub kind: LispErrorKind,
+}
+
+impl fmt::Display for LispErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LispErrorKind::*;
+        match self {
+            UnexpectedCharacter(c) => write!(f, "Unexpected character {:?}", c),
+            UnterminatedString => write!(f, "Unterminated string"),
+            UnopenedParen => write!(f, "Unmatched ')'"),
+            UnclosedParen => write!(f, "Unclosed '('"),
+            UnknownFunction(s) => write!(f, "Unknown function {:?}", s),
+            UnboundName(s) => write!(f, "{:?} is not a value", s),
+            UnexpectedString => write!(f, "Strings can only be printed"),
+            NumberOutOfRange(s) => write!(f, "Number {} is out of range", s),
+            MissingFunction => write!(f, "Expected a function name"),
+            WrongArgumentCount(s) => write!(f, "Wrong number of arguments to {:?}", s),
         }
     }
+}
 
-    println!();
+impl fmt::Display for LispError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line_number, self.column, self.kind)
+    }
+}
+
+impl Error for LispError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+// BF which prints the current cell in decimal, leaving it and the cursor as they were. Uses the
+// nine cells to the right, which have to be zero.
+const PRINT_DECIMAL: &str = ">>++++++++++<<[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]>>[-]>>>++++++++++<[->-[>+>>]>[+[-<+>]>+>>]<<<<<]>[-]>>[>++++++[-<++++++++>]<.<<+>+>[-]]<[<[->-<]++++++[->++++++++<]>.[-]]<<++++++[-<++++++++>]<.[-]<<[-<+>]<";
+
+struct Codegen<'a> {
+    ast: &'a Ast,
+    bytecode: Tape<u8>,
+    opt_level: OptLevel,
+}
+
+impl<'a> Codegen<'a> {
+    fn emit(&mut self, op: Opcode) {
+        self.bytecode.push(op.into());
+    }
+
+    fn emit_with(&mut self, op: Opcode, operand: u8) {
+        self.bytecode.push(op.into());
+        self.bytecode.push(operand);
+    }
+
+    fn emit_long(&mut self, op: Opcode, operand: i16) {
+        self.bytecode.push(op.into());
+        self.bytecode.push_int(2, operand as u16 as u32);
+    }
+
+    fn emit_relative(&mut self, op: Opcode, offset: i16, operand: u8) {
+        self.bytecode.push(op.into());
+        self.bytecode.push_int(2, offset as u16 as u32);
+        self.bytecode.push(operand);
+    }
+
+    // Pop the top of the stack into the cell `offset` to the right
+    fn pop_into(&mut self, offset: u8) {
+        self.emit_with(Opcode::MoveTapeShort, offset);
+        self.emit(Opcode::Pop);
+        self.emit_with(Opcode::MoveTapeShort, offset.wrapping_neg());
+    }
+
+    fn print_decimal(&mut self) {
+        let nodes = bf::parse(PRINT_DECIMAL).expect("decimal printing BF is malformed");
+        let nodes = bf::optimize(nodes, self.opt_level);
+        bf::emit(
+            &nodes,
+            &mut self.bytecode,
+            &mut SourceMap::new(),
+            self.opt_level >= OptLevel::O3,
+        );
+    }
+
+    fn error(&self, index: usize, kind: LispErrorKind) -> LispError {
+        let node = &self.ast.nodelist[index];
+        LispError {
+            line_number: node.line_number,
+            column: node.column,
+            kind,
+        }
+    }
+
+    // Generate code pushing the value of the expression at `index`
+    fn expression(&mut self, index: usize) -> Result<(), LispError> {
+        let node = &self.ast.nodelist[index];
+        match node.kind {
+            TokenKind::NumberLiteral => {
+                let n = node.raw.parse().map_err(|_| {
+                    self.error(index, LispErrorKind::NumberOutOfRange(node.raw.clone()))
+                })?;
+                self.emit_with(Opcode::Set, n);
+                self.emit(Opcode::Push);
+                Ok(())
+            }
+            TokenKind::StringLiteral => Err(self.error(index, LispErrorKind::UnexpectedString)),
+            TokenKind::Name => Err(self.error(index, LispErrorKind::UnboundName(node.raw.clone()))),
+            _ => self.call(index),
+        }
+    }
 
-    ast
+    fn call(&mut self, index: usize) -> Result<(), LispError> {
+        let children: &[usize] = match self.ast.nodelist[index].children {
+            Some(ref children) => children,
+            None => &[],
+        };
+        let (function, name, args) = match children.split_first() {
+            Some((&first, args)) if matches!(self.ast.nodelist[first].kind, TokenKind::Name) => {
+                (first, self.ast.nodelist[first].raw.as_str(), args)
+            }
+            _ => return Err(self.error(index, LispErrorKind::MissingFunction)),
+        };
+
+        match name {
+            "add" | "sub" => {
+                let (&first, rest) = match args.split_first() {
+                    Some(split) => split,
+                    None if name == "add" => {
+                        self.emit_with(Opcode::Set, 0);
+                        self.emit(Opcode::Push);
+                        return Ok(());
+                    }
+                    None => {
+                        return Err(
+                            self.error(index, LispErrorKind::WrongArgumentCount(name.to_string()))
+                        )
+                    }
+                };
+                self.expression(first)?;
+                if name == "sub" && rest.is_empty() {
+                    // negation
+                    self.pop_into(1);
+                    self.emit_with(Opcode::Set, 0);
+                    self.emit_long(Opcode::SubRelativeLong, 1);
+                    self.emit_relative(Opcode::SetRelativeLong, 1, 0);
+                    self.emit(Opcode::Push);
+                }
+                for &arg in rest {
+                    self.expression(arg)?;
+                    self.pop_into(1);
+                    self.emit(Opcode::Pop);
+                    if name == "add" {
+                        // cell 0 -= cell 1 * -1
+                        self.emit(Opcode::IncTape);
+                        self.emit_relative(Opcode::MulSubRelativeLong, -1, 255);
+                        self.emit_with(Opcode::Set, 0);
+                        self.emit(Opcode::DecTape);
+                    } else {
+                        self.emit_long(Opcode::SubRelativeLong, 1);
+                        self.emit_relative(Opcode::SetRelativeLong, 1, 0);
+                    }
+                    self.emit(Opcode::Push);
+                }
+                Ok(())
+            }
+            "print" => {
+                for (i, &arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.emit_with(Opcode::Set, b' ');
+                        self.emit(Opcode::OutputByte);
+                    }
+                    let node = &self.ast.nodelist[arg];
+                    if let TokenKind::StringLiteral = node.kind {
+                        for &b in node.raw.as_bytes() {
+                            self.emit_with(Opcode::Set, b);
+                            self.emit(Opcode::OutputByte);
+                        }
+                    } else {
+                        self.expression(arg)?;
+                        self.emit(Opcode::Pop);
+                        self.print_decimal();
+                    }
+                }
+                self.emit_with(Opcode::Set, b'\n');
+                self.emit(Opcode::OutputByte);
+                self.emit_with(Opcode::Set, 0);
+                self.emit(Opcode::Push);
+                Ok(())
+            }
+            _ => Err(self.error(function, LispErrorKind::UnknownFunction(name.to_string()))),
+        }
+    }
+}
+
+/// Generate bytecode evaluating each top level expression of a program in turn
+pub fn compile(ast: &Ast, opt_level: OptLevel) -> Result<Tape<u8>, LispError> {
+    let mut codegen = Codegen {
+        ast,
+        bytecode: Tape::new(vec![]),
+        opt_level,
+    };
+    // as in BF, so that the program does not start at a jump target
+    codegen.emit(Opcode::Nop);
+
+    let expressions = ast.nodelist[0].children.clone().unwrap_or_default();
+    for index in expressions {
+        codegen.expression(index)?;
+        // the value is not used
+        codegen.emit(Opcode::Pop);
+    }
+    codegen.emit(Opcode::HaltAlways);
+    Ok(codegen.bytecode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LispErrorKind::*;
+    use super::TokenKind::*;
+    use super::{parse, tokenize, LispError};
+
+    fn error(line_number: usize, column: usize, kind: super::LispErrorKind) -> LispError {
+        LispError {
+            line_number,
+            column,
+            kind,
+        }
+    }
+
+    #[test]
+    fn tokenize_test() {
+        let tokens = tokenize("(print \"a\nb\"\n  12 x)").unwrap();
+        let summary: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.raw.as_str(), t.line_number, t.column))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("(", 1, 1),
+                ("print", 1, 2),
+                ("a\nb", 1, 8),
+                ("12", 3, 3),
+                ("x", 3, 6),
+                (")", 3, 7),
+            ]
+        );
+        assert!(matches!(tokens[2].kind, StringLiteral));
+        assert!(matches!(tokens[3].kind, NumberLiteral));
+
+        // columns count characters rather than bytes
+        let tokens = tokenize("(print \"é\" x)").unwrap();
+        assert_eq!((tokens[3].raw.as_str(), tokens[3].column), ("x", 12));
+        let tokens = tokenize("(print \"é\né\" x)").unwrap();
+        assert_eq!(
+            (
+                tokens[3].raw.as_str(),
+                tokens[3].line_number,
+                tokens[3].column
+            ),
+            ("x", 2, 4)
+        );
+
+        assert_eq!(
+            tokenize("(add 1\n  [2])").unwrap_err(),
+            error(2, 3, UnexpectedCharacter('['))
+        );
+        assert_eq!(
+            tokenize("(print\n \"abc)").unwrap_err(),
+            error(2, 2, UnterminatedString)
+        );
+    }
+
+    #[test]
+    fn parse_test() {
+        let ast = parse(tokenize("(add 1 (sub 2))\n5").unwrap()).unwrap();
+        let root = ast.nodelist[0].children.clone().unwrap();
+        assert_eq!(root.len(), 2);
+        let call = &ast.nodelist[root[0]];
+        assert!(matches!(call.kind, CallExpression));
+        assert_eq!(call.children.as_ref().unwrap().len(), 3);
+        let five = &ast.nodelist[root[1]];
+        assert_eq!(
+            (five.raw.as_str(), five.line_number, five.column),
+            ("5", 2, 1)
+        );
+
+        let parsed = |source| parse(tokenize(source).unwrap()).unwrap_err();
+        assert_eq!(parsed("(add 1))"), error(1, 8, UnopenedParen));
+        assert_eq!(parsed("(add 1\n  (sub 2)"), error(1, 1, UnclosedParen));
+        assert_eq!(parsed("(add (sub 2"), error(1, 6, UnclosedParen));
+        assert_eq!(parsed("(add (sub 2)"), error(1, 1, UnclosedParen));
+    }
 }
@@ -0,0 +1,309 @@
+/*!
+ * Object file format for compiled programs
+ *
+ * ```text
+ * magic      4 bytes   "STVM"
+ * version    2 bytes   format version, currently 1
+ * lang       1 byte    source language (0 = raw, 1 = bf, 2 = lisp)
+ * sections   until the end of the file, each one being
+ *     id     1 byte
+ *     length 4 bytes
+ *     data   `length` bytes
+ * ```
+ *
+ * All integers are big-endian, like the operands in bytecode. The bytecode
+ * section is required; the source section is debug information which may
+ * be left out. Sections with an unknown id are skipped when loading.
+ *
+ * The source map section is a list of entries of five 4 byte integers:
+ * bytecode offset, start line, start column, end line and end column.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::sourcemap::{SourceMap, SourcePosition, SourceSpan};
+use super::Lang;
+
+pub const MAGIC: &[u8; 4] = b"STVM";
+pub const VERSION: u16 = 1;
+
+const SECTION_BYTECODE: u8 = 1;
+const SECTION_SOURCE: u8 = 2;
+const SECTION_SOURCE_MAP: u8 = 3;
+
+#[derive(Debug)]
+pub enum ObjectError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownLang(u8),
+    // The file ends in the middle of the header or a section
+    Truncated,
+    MissingBytecode,
+    // The source section is not valid UTF-8
+    InvalidSource,
+    // The source map section is not made of whole entries
+    InvalidSourceMap,
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ObjectError::*;
+        match self {
+            Io(e) => write!(f, "I/O Error: {}", e),
+            BadMagic => write!(f, "Not an STVM object file"),
+            UnsupportedVersion(v) => write!(f, "Unsupported object file version {}", v),
+            UnknownLang(n) => write!(f, "Unknown source language {}", n),
+            Truncated => write!(f, "Object file is truncated"),
+            MissingBytecode => write!(f, "Object file has no bytecode section"),
+            InvalidSource => write!(f, "Source section is not valid UTF-8"),
+            InvalidSourceMap => write!(f, "Source map section is malformed"),
+        }
+    }
+}
+
+impl Error for ObjectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ObjectError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ObjectError {
+    fn from(e: io::Error) -> ObjectError {
+        ObjectError::Io(e)
+    }
+}
+
+/// The contents of an object file
+#[derive(Debug)]
+pub struct Object {
+    pub lang: Lang,
+    pub bytecode: Vec<u8>,
+    pub sourcecode: Option<String>,
+    pub source_map: Option<SourceMap>,
+}
+
+fn lang_to_u8(lang: Lang) -> u8 {
+    match lang {
+        Lang::Raw => 0,
+        Lang::Bf => 1,
+        Lang::Lisp => 2,
+    }
+}
+
+fn lang_from_u8(n: u8) -> Option<Lang> {
+    match n {
+        0 => Some(Lang::Raw),
+        1 => Some(Lang::Bf),
+        2 => Some(Lang::Lisp),
+        _ => None,
+    }
+}
+
+fn encode_source_map(source_map: &SourceMap) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &(offset, span) in source_map.iter() {
+        for &n in &[
+            offset,
+            span.start.line,
+            span.start.column,
+            span.end.line,
+            span.end.column,
+        ] {
+            bytes.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+    }
+    bytes
+}
+
+fn decode_source_map(bytes: &[u8]) -> Result<SourceMap, ObjectError> {
+    if !bytes.len().is_multiple_of(20) {
+        return Err(ObjectError::InvalidSourceMap);
+    }
+    let mut source_map = SourceMap::new();
+    for entry in bytes.chunks(20) {
+        let n: Vec<usize> = entry
+            .chunks(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .collect();
+        let start = SourcePosition {
+            line: n[1],
+            column: n[2],
+        };
+        let end = SourcePosition {
+            line: n[3],
+            column: n[4],
+        };
+        // positions count from 1, and a span cannot end before it starts
+        let ordered = (end.line, end.column) >= (start.line, start.column);
+        if start.line == 0 || start.column == 0 || end.line == 0 || end.column == 0 || !ordered {
+            return Err(ObjectError::InvalidSourceMap);
+        }
+        source_map.insert(n[0], SourceSpan::new(start, end));
+    }
+    Ok(source_map)
+}
+
+fn push_section(bytes: &mut Vec<u8>, id: u8, data: &[u8]) {
+    bytes.push(id);
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(data);
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.push(lang_to_u8(self.lang));
+
+        push_section(&mut bytes, SECTION_BYTECODE, &self.bytecode);
+        if let Some(ref sourcecode) = self.sourcecode {
+            push_section(&mut bytes, SECTION_SOURCE, sourcecode.as_bytes());
+        }
+        if let Some(ref source_map) = self.source_map {
+            push_section(
+                &mut bytes,
+                SECTION_SOURCE_MAP,
+                &encode_source_map(source_map),
+            );
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, ObjectError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(ObjectError::BadMagic);
+        }
+        if bytes.len() < 7 {
+            return Err(ObjectError::Truncated);
+        }
+
+        let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if version != VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+        let lang = lang_from_u8(bytes[6]).ok_or(ObjectError::UnknownLang(bytes[6]))?;
+
+        let mut bytecode = None;
+        let mut sourcecode = None;
+        let mut source_map = None;
+
+        let mut rest = &bytes[7..];
+        while !rest.is_empty() {
+            if rest.len() < 5 {
+                return Err(ObjectError::Truncated);
+            }
+            let id = rest[0];
+            let length = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+            rest = &rest[5..];
+            if rest.len() < length {
+                return Err(ObjectError::Truncated);
+            }
+            let data = &rest[..length];
+            rest = &rest[length..];
+
+            match id {
+                SECTION_BYTECODE => bytecode = Some(data.to_vec()),
+                SECTION_SOURCE => {
+                    let s =
+                        String::from_utf8(data.to_vec()).map_err(|_| ObjectError::InvalidSource)?;
+                    sourcecode = Some(s);
+                }
+                SECTION_SOURCE_MAP => source_map = Some(decode_source_map(data)?),
+                _ => (),
+            }
+        }
+
+        Ok(Object {
+            lang,
+            bytecode: bytecode.ok_or(ObjectError::MissingBytecode)?,
+            sourcecode,
+            source_map,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sourcemap::{SourceMap, SourcePosition, SourceSpan};
+    use super::super::Lang;
+    use super::{Object, ObjectError};
+
+    #[test]
+    fn round_trip_test() {
+        let object = Object {
+            lang: Lang::Bf,
+            bytecode: vec![0, 1, 2, 22],
+            sourcecode: Some("+".to_string()),
+            source_map: None,
+        };
+        let bytes = object.to_bytes();
+        assert_eq!(&bytes[..7], b"STVM\x00\x01\x01");
+
+        let loaded = Object::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.lang, Lang::Bf);
+        assert_eq!(loaded.bytecode, object.bytecode);
+        assert_eq!(loaded.sourcecode, object.sourcecode);
+    }
+
+    #[test]
+    fn load_error_test() {
+        let mut bytes = Object {
+            lang: Lang::Raw,
+            bytecode: vec![22],
+            sourcecode: None,
+            source_map: None,
+        }
+        .to_bytes();
+
+        // unknown sections are skipped
+        bytes.extend_from_slice(&[0x7f, 0, 0, 0, 1, 0xaa]);
+        assert!(Object::from_bytes(&bytes).is_ok());
+
+        match Object::from_bytes(&bytes[..bytes.len() - 1]) {
+            Err(ObjectError::Truncated) => (),
+            other => panic!("expected Truncated, got {:?}", other),
+        }
+        match Object::from_bytes(b"MVTS\x00\x01\x00") {
+            Err(ObjectError::BadMagic) => (),
+            other => panic!("expected BadMagic, got {:?}", other),
+        }
+        match Object::from_bytes(b"STVM\x00\x02\x00") {
+            Err(ObjectError::UnsupportedVersion(2)) => (),
+            other => panic!("expected UnsupportedVersion, got {:?}", other),
+        }
+        match Object::from_bytes(b"STVM\x00\x01\x00") {
+            Err(ObjectError::MissingBytecode) => (),
+            other => panic!("expected MissingBytecode, got {:?}", other),
+        }
+
+        let at = |line, column| SourcePosition { line, column };
+        for &(start, end) in &[
+            (at(0, 1), at(1, 1)),
+            (at(1, 0), at(1, 1)),
+            (at(2, 1), at(1, 5)),
+            (at(1, 5), at(1, 4)),
+        ] {
+            let mut source_map = SourceMap::new();
+            source_map.insert(0, SourceSpan::new(start, end));
+            let bytes = Object {
+                lang: Lang::Bf,
+                bytecode: vec![22],
+                sourcecode: None,
+                source_map: Some(source_map),
+            }
+            .to_bytes();
+            match Object::from_bytes(&bytes) {
+                Err(ObjectError::InvalidSourceMap) => (),
+                other => panic!("expected InvalidSourceMap, got {:?}", other),
+            }
+        }
+    }
+}
@@ -30,7 +30,7 @@ impl Prng {
             i = 0;
         }
         let mut s0 = (i << 8) ^ i;
-        i = s0 << 8 | s0 >> 8;
+        i = s0.rotate_right(8);
         s0 = i ^ ((s0 & 0xff) << 1);
         let s1 = 0xff80 ^ (s0 >> 1);
         (if s0 & 1 == 1 { 0x8180 } else { 0x1ff4 } ^ s1)
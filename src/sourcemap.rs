@@ -0,0 +1,100 @@
+use std::fmt;
+
+/// A line and column in source code, both counted from 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourcePosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The source code an instruction was compiled from, `end` being the last character of it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceSpan {
+    pub start: SourcePosition,
+    pub end: SourcePosition,
+}
+
+/// Mapping from bytecode offsets to the source code each instruction came from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    // sorted by offset
+    entries: Vec<(usize, SourceSpan)>,
+}
+
+impl fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl SourceSpan {
+    pub fn new(start: SourcePosition, end: SourcePosition) -> SourceSpan {
+        SourceSpan { start, end }
+    }
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Record where the instruction at `offset` came from
+    pub fn insert(&mut self, offset: usize, span: SourceSpan) {
+        match self.entries.binary_search_by_key(&offset, |&(o, _)| o) {
+            Ok(i) => self.entries[i].1 = span,
+            Err(i) => self.entries.insert(i, (offset, span)),
+        }
+    }
+
+    /// Find the source of the instruction starting at `offset`
+    pub fn lookup(&self, offset: usize) -> Option<SourceSpan> {
+        self.entries
+            .binary_search_by_key(&offset, |&(o, _)| o)
+            .ok()
+            .map(|i| self.entries[i].1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (usize, SourceSpan)> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SourceMap, SourcePosition, SourceSpan};
+
+    #[test]
+    fn lookup_test() {
+        let at = |line, column| SourcePosition { line, column };
+        let mut map = SourceMap::new();
+        map.insert(5, SourceSpan::new(at(1, 3), at(1, 4)));
+        map.insert(1, SourceSpan::new(at(1, 1), at(1, 2)));
+
+        assert_eq!(map.lookup(1), Some(SourceSpan::new(at(1, 1), at(1, 2))));
+        assert_eq!(map.lookup(5), Some(SourceSpan::new(at(1, 3), at(1, 4))));
+        assert_eq!(map.lookup(3), None);
+        assert_eq!(format!("{}", map.lookup(5).unwrap()), "1:3-1:4");
+    }
+}
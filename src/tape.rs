@@ -87,16 +87,12 @@ impl<T: Copy> Tape<T> {
         self.cursor
     }
 
-    pub fn jump_relative(&mut self, target: isize) {
-        let c = self.get_cursor() as isize;
-        self.jump((c + target) as usize);
-    }
-
-    pub fn jump(&mut self, target: usize) {
+    pub fn jump(&mut self, target: usize) -> Result<(), TapeError> {
         if target >= self.data.len() {
-            panic!("Tape pointer jump target {} is outside right bound", target)
+            return Err(TapeError::OutOfBounds);
         };
         self.cursor = target;
+        Ok(())
     }
 
     pub fn peek(&self) -> T {
@@ -104,42 +100,24 @@ impl<T: Copy> Tape<T> {
     }
 
     pub fn peek_at(&self, index: usize) -> Result<T, TapeError> {
-        if index <= self.len() {
+        if index < self.len() {
             Ok(self[index])
         } else {
             Err(TapeError::OutOfBounds)
         }
     }
 
-    pub fn read_inc(&mut self) -> (T, bool) {
-        let n = self.peek();
-        (n, self.inc_cursor())
-    }
-
-    /*
-        pub fn inc_read(&mut self) -> Result<T, TapeError> {
-            if self.inc_cursor() {
-                Ok(self[self.cursor])
-            } else {
-                Err(TapeError::Eof)
-            }
-        }
-    */
-
-    pub fn inc_cursor(&mut self) -> bool {
-        if self.cursor == self.len() {
-            return false;
-        }
-        self.cursor += 1;
-        true
+    // Move the cursor forward, stopping just past the end
+    pub fn skip(&mut self, n: usize) {
+        self.cursor = self.data.len().min(self.cursor + n);
     }
 
-    pub fn peek_relative(&mut self, offset: isize) -> T {
+    pub fn peek_relative(&self, offset: isize) -> Result<T, TapeError> {
         let target = self.get_cursor() as isize + offset;
-        if target < 0 || (target as usize) >= self.data.len() {
-            panic!("Tape peek relative target {} is outside bounds", target)
+        if target < 0 {
+            return Err(TapeError::OutOfBounds);
         };
-        self[target as usize]
+        self.peek_at(target as usize)
     }
 
     pub fn write_at(&mut self, index: usize, value: T) {
@@ -176,6 +154,15 @@ impl<T: Copy> Tape<T> {
         self.data.pop().unwrap()
     }
 
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
     pub fn iter(&self) -> std::slice::Iter<'_, T> {
         self.data.iter()
     }
@@ -183,20 +170,20 @@ impl<T: Copy> Tape<T> {
 
 // TODO: change this to not require Clone when Vec.resize_with() is out of nightly
 impl<T: Default + Copy> Tape<T> {
-    pub fn move_cursor(&mut self, change: isize) -> bool {
+    pub fn move_cursor(&mut self, change: isize) -> Result<bool, TapeError> {
         let m = self.cursor as isize + change;
         if m < 0 {
-            panic!("Tape pointer outside left bound")
+            return Err(TapeError::OutOfBounds);
         };
         let outside_right_bound = m >= self.data.len() as isize;
         if outside_right_bound {
             self.grow((m + 1) as usize);
         };
         self.cursor = m as usize;
-        outside_right_bound
+        Ok(outside_right_bound)
     }
 
-    pub fn grow(&mut self, new_size: usize) -> () {
+    pub fn grow(&mut self, new_size: usize) {
         if new_size > self.data.len() {
             self.data.resize(new_size, T::default())
         }
@@ -207,7 +194,7 @@ impl Tape<u8> {
     pub fn _peek_u32(&self, index: usize) -> Result<u32, TapeError> {
         use std::convert::TryInto;
 
-        if index >= self.data.len() {
+        if index + 4 > self.data.len() {
             //~ self.grow(index + 1);
             return Err(TapeError::OutOfBounds);
         }
@@ -216,41 +203,91 @@ impl Tape<u8> {
         Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
     }
 
-    pub fn i8_subtract(&mut self, n: i8) -> bool {
-        let m = self.data[self.cursor] as i8;
-        let (v, overflow) = m.overflowing_add(-n);
-        self.data[self.cursor] = v as u8;
-        overflow
-    }
-
-    pub fn read_int(&mut self, bytes: usize) -> Result<u32, TapeError> {
-        if bytes == 0 {
-            Err(TapeError::InvalidArgument)
-        } else if bytes > 4 {
-            Err(TapeError::InvalidArgument)
+    // Move the cursor `stride` cells at a time until it is on a zero cell. Seeking right past the
+    // end grows the tape, in which case true is returned like `move_cursor`.
+    pub fn seek_zero(&mut self, stride: usize, forward: bool) -> Result<bool, TapeError> {
+        if stride == 0 {
+            return Err(TapeError::InvalidArgument);
+        }
+        if forward {
+            let found = if stride == 1 {
+                self.data[self.cursor..].iter().position(|&b| b == 0)
+            } else {
+                self.data[self.cursor..].iter().step_by(stride).position(|&b| b == 0)
+            };
+            match found {
+                Some(i) => {
+                    self.cursor += i * stride;
+                    Ok(false)
+                }
+                None => {
+                    // every cell past the end is zero, so stop at the first one landed on
+                    let steps = (self.data.len() - self.cursor).div_ceil(stride);
+                    self.cursor += steps * stride;
+                    self.grow(self.cursor + 1);
+                    Ok(true)
+                }
+            }
         } else {
-            let mut n: u32 = 0;
-            for _i in 0..bytes {
-                let (byte, success) = self.read_inc();
-                let byte = byte as u8;
-                n = (n << 8) | (byte as u32);
-                if !success {
-                    return Err(TapeError::OutOfBounds);
+            let found = if stride == 1 {
+                self.data[..=self.cursor].iter().rposition(|&b| b == 0)
+            } else {
+                self.data[..=self.cursor]
+                    .iter()
+                    .rev()
+                    .step_by(stride)
+                    .position(|&b| b == 0)
+                    .map(|i| self.cursor - i * stride)
+            };
+            match found {
+                Some(i) => {
+                    self.cursor = i;
+                    Ok(false)
                 }
+                None => Err(TapeError::OutOfBounds),
             }
-            Ok(n)
         }
     }
 
+    // Write to the cell `offset` away from the cursor, growing the tape if it is past the end
+    pub fn write_relative(&mut self, offset: isize, value: u8) -> Result<(), TapeError> {
+        let index = self.cursor as isize + offset;
+        if index < 0 {
+            return Err(TapeError::OutOfBounds);
+        }
+        self.grow(index as usize + 1);
+        self.data[index as usize] = value;
+        Ok(())
+    }
+
+    // Subtract from the cell `offset` away from the cursor, growing the tape if it is past the end.
+    // Returns whether the subtraction overflowed, like `i8_subtract`.
+    pub fn i8_subtract_relative(&mut self, offset: isize, n: i8) -> Result<bool, TapeError> {
+        let index = self.cursor as isize + offset;
+        if index < 0 {
+            return Err(TapeError::OutOfBounds);
+        }
+        let index = index as usize;
+        self.grow(index + 1);
+        let (v, overflow) = (self.data[index] as i8).overflowing_sub(n);
+        self.data[index] = v as u8;
+        Ok(overflow)
+    }
+
+    pub fn i8_subtract(&mut self, n: i8) -> bool {
+        let m = self.data[self.cursor] as i8;
+        let (v, overflow) = m.overflowing_sub(n);
+        self.data[self.cursor] = v as u8;
+        overflow
+    }
+
     pub fn peek_int(&self, mut index: usize, bytes: usize) -> Result<u32, TapeError> {
-        if bytes == 0 {
-            Err(TapeError::InvalidArgument)
-        } else if bytes > 4 {
+        if bytes == 0 || bytes > 4 {
             Err(TapeError::InvalidArgument)
         } else {
             let mut n: u32 = 0;
             for _i in 0..bytes {
-                let byte = self.peek_at(index)? as u8;
+                let byte = self.peek_at(index)?;
                 index += 1;
                 n = (n << 8) | (byte as u32);
             }
@@ -259,28 +296,26 @@ impl Tape<u8> {
     }
 
     pub fn push_int(&mut self, bytes: usize, n: u32) {
-        if bytes == 0 {
+        if bytes == 0 || bytes > 4 {
             return;
-        } else if bytes > 4 {
-            return;
-        } else {
-            for i in 0..bytes {
-                let shift = 8 * (bytes - i - 1);
-                self.push(((n & (0xff << shift)) >> shift) as u8);
-            }
+        }
+        for i in 0..bytes {
+            let shift = 8 * (bytes - i - 1);
+            self.push(((n & (0xff << shift)) >> shift) as u8);
         }
     }
 
-    pub fn write_int_at(&mut self, index: usize, bytes: usize, n: u32) {
-        if bytes == 0 {
-            panic!();
-        } else if bytes > 4 {
-            panic!();
+    pub fn write_int_at(&mut self, index: usize, bytes: usize, n: u32) -> Result<(), TapeError> {
+        if bytes == 0 || bytes > 4 {
+            Err(TapeError::InvalidArgument)
+        } else if index + bytes > self.len() {
+            Err(TapeError::OutOfBounds)
         } else {
             for i in 0..bytes {
                 let shift = 8 * (bytes - i - 1);
                 self.write_at(index + i, ((n & (0xff << shift)) >> shift) as u8);
             }
+            Ok(())
         }
     }
 }
@@ -296,7 +331,7 @@ impl fmt::Display for Tape<u8> {
 
             let length = com.len();
 
-            if index + length - 1 >= bytecode.len() {
+            if index + length > bytecode.len() {
                 return None;
             }
 
@@ -305,7 +340,7 @@ impl fmt::Display for Tape<u8> {
                 s = format!(
                     "{} {:02x}",
                     s,
-                    bytecode.peek_at(index + i).expect("Unexpected end of tape") as u8
+                    bytecode.peek_at(index + i).expect("Unexpected end of tape")
                 );
             }
 
@@ -314,14 +349,12 @@ impl fmt::Display for Tape<u8> {
             if length == 1 {
                 s = format!("{} {:?}\n", s, com);
             } else {
-                s = format!(
-                    "{} {:?} {}\n",
-                    s,
-                    com,
-                    bytecode
-                        .peek_int(index + 1, length - 1)
-                        .expect("Unexpected end of tape") as i32
-                );
+                let operands = com.decode_operands(&bytecode.data[index + 1..index + length]);
+                s = format!("{} {:?}", s, com);
+                for n in operands {
+                    s = format!("{} {}", s, n);
+                }
+                s = format!("{}\n", s);
             }
 
             //s = format!("{}\n", s);
@@ -333,7 +366,7 @@ impl fmt::Display for Tape<u8> {
 
         let mut index = 0;
         while index < self.len() {
-            if let Some((s, n)) = format_command(&self, index) {
+            if let Some((s, n)) = format_command(self, index) {
                 full_output += &s;
                 index += n;
             } else {
@@ -354,8 +387,58 @@ impl fmt::Display for Tape<u8> {
 
 #[cfg(test)]
 mod tests {
+    use super::{Tape, TapeError};
+
     #[test]
     fn u8_test() {
     }
+
+    #[test]
+    fn bounds_test() {
+        let mut tape = Tape::new(vec![0u8, 1, 2]);
+
+        match tape.move_cursor(-1) {
+            Err(TapeError::OutOfBounds) => (),
+            other => panic!("expected OutOfBounds, got {:?}", other),
+        }
+        assert!(tape.jump(3).is_err());
+        assert!(tape.peek_relative(-1).is_err());
+        assert!(tape.peek_relative(3).is_err());
+        assert!(tape.write_int_at(0, 5, 0).is_err());
+        assert!(tape.write_int_at(1, 4, 0).is_err());
+
+        assert!(tape.move_cursor(4).unwrap());
+        assert_eq!(tape.len(), 5);
+
+        // subtracting -128 has no negation which fits in an i8
+        assert!(tape.i8_subtract(-128));
+        assert_eq!(tape.peek(), 128);
+        assert!(tape.i8_subtract_relative(-1, -128).unwrap());
+        assert_eq!(tape.peek_relative(-1).unwrap(), 128);
+    }
+
+    #[test]
+    fn seek_test() {
+        let mut tape = Tape::new(vec![1u8, 1, 1, 0, 1, 1, 1]);
+
+        assert!(!tape.seek_zero(1, true).unwrap());
+        assert_eq!(tape.get_cursor(), 3);
+        assert!(!tape.seek_zero(1, true).unwrap());
+        assert_eq!(tape.get_cursor(), 3);
+
+        tape.jump(0).unwrap();
+        assert!(tape.seek_zero(2, true).unwrap());
+        assert_eq!(tape.get_cursor(), 8);
+        assert_eq!(tape.len(), 9);
+
+        tape.jump(5).unwrap();
+        assert!(!tape.seek_zero(1, false).unwrap());
+        assert_eq!(tape.get_cursor(), 3);
+        tape.jump(5).unwrap();
+        assert!(!tape.seek_zero(2, false).unwrap());
+        assert_eq!(tape.get_cursor(), 3);
+        tape.jump(4).unwrap();
+        assert!(tape.seek_zero(3, false).is_err());
+    }
 }
 
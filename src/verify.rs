@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use super::command::Opcode;
+use super::tape::Tape;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    // Byte which does not encode any opcode
+    InvalidOpcode(u8),
+    // Opcode which exists but can not be executed by the VM
+    UnexpectedCommand(Opcode),
+    // The bytecode ends before all of the opcode's operand bytes
+    TruncatedOperand(Opcode),
+    // Jump to a location past the end of the bytecode
+    JumpOutOfBounds(isize),
+    // Jump into the middle of an instruction
+    JumpMisaligned(usize),
+    // Seek moving 0 cells at a time, which would never find anything
+    ZeroStride(Opcode),
+}
+
+/// A problem found in bytecode, and the offset of the offending instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::VerifyErrorKind::*;
+        match self {
+            InvalidOpcode(byte) => write!(f, "Invalid opcode {:#04x}", byte),
+            UnexpectedCommand(op) => write!(f, "{:?} can not be executed", op),
+            TruncatedOperand(op) => write!(f, "Operand of {:?} is cut off", op),
+            JumpOutOfBounds(target) => write!(f, "Jump target {} is out of bounds", target),
+            JumpMisaligned(target) => {
+                write!(
+                    f,
+                    "Jump target {} is not the start of an instruction",
+                    target
+                )
+            }
+            ZeroStride(op) => write!(f, "{:?} has a stride of 0", op),
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}: {}", self.offset, self.kind)
+    }
+}
+
+impl Error for VerifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// Check that bytecode consists of whole, executable instructions and that
+/// every jump lands on one of them. All problems found are returned.
+pub fn verify(bytecode: &Tape<u8>) -> Result<(), Vec<VerifyError>> {
+    use self::Opcode::*;
+    use self::VerifyErrorKind::*;
+
+    let mut errors = vec![];
+    let mut boundaries = HashSet::new();
+    // (offset of the jump, target)
+    let mut jumps = vec![];
+
+    let mut offset = 0;
+    while offset < bytecode.len() {
+        boundaries.insert(offset);
+
+        let byte = bytecode[offset];
+        let op = match Opcode::from_u8(byte) {
+            Some(op) => op,
+            None => {
+                errors.push(VerifyError {
+                    offset,
+                    kind: InvalidOpcode(byte),
+                });
+                offset += 1;
+                continue;
+            }
+        };
+
+        let length = op.len();
+        if offset + length > bytecode.len() {
+            errors.push(VerifyError {
+                offset,
+                kind: TruncatedOperand(op),
+            });
+            break;
+        }
+
+        let next = (offset + length) as isize;
+        match op {
+            StartLoop | EndLoop => errors.push(VerifyError {
+                offset,
+                kind: UnexpectedCommand(op),
+            }),
+            JumpRelativeShortIfZero | JumpRelativeShortIfNonzero => {
+                let n = bytecode[offset + 1] as i8 as isize;
+                jumps.push((offset, next + n));
+            }
+            JumpRelativeLongIfZero | JumpRelativeLongIfNonzero => {
+                let n = bytecode.peek_int(offset + 1, 2).unwrap() as u16 as i16 as isize;
+                jumps.push((offset, next + n));
+            }
+            JumpAbsoluteIfZero | JumpAbsoluteIfNonzero => {
+                let n = bytecode.peek_int(offset + 1, 4).unwrap() as isize;
+                jumps.push((offset, n));
+            }
+            MoveTapeShortJumpAbsoluteIfNonzero => {
+                let n = bytecode.peek_int(offset + 2, 4).unwrap() as isize;
+                jumps.push((offset, n));
+            }
+            SeekRightStride | SeekLeftStride if bytecode[offset + 1] == 0 => {
+                errors.push(VerifyError {
+                    offset,
+                    kind: ZeroStride(op),
+                })
+            }
+            _ => (),
+        }
+
+        offset += length;
+    }
+
+    for (offset, target) in jumps {
+        if target < 0 || target as usize >= bytecode.len() {
+            errors.push(VerifyError {
+                offset,
+                kind: JumpOutOfBounds(target),
+            });
+        } else if !boundaries.contains(&(target as usize)) {
+            errors.push(VerifyError {
+                offset,
+                kind: JumpMisaligned(target as usize),
+            });
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        errors.sort_by_key(|e| e.offset);
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::command::Opcode::*;
+    use super::super::tape::Tape;
+    use super::VerifyErrorKind::*;
+    use super::{verify, VerifyError};
+
+    #[test]
+    fn valid_test() {
+        let bytecode = Tape::new(vec![
+            Nop.into(),
+            JumpAbsoluteIfZero.into(),
+            0,
+            0,
+            0,
+            9,
+            Dec.into(),
+            JumpRelativeShortIfNonzero.into(),
+            (-3i8) as u8,
+            HaltAlways.into(),
+        ]);
+        assert_eq!(verify(&bytecode), Ok(()));
+    }
+
+    #[test]
+    fn invalid_test() {
+        let bytecode = Tape::new(vec![
+            0xff,
+            JumpAbsoluteIfZero.into(),
+            0,
+            0,
+            0,
+            2,
+            JumpRelativeShortIfNonzero.into(),
+            100,
+            StartLoop.into(),
+            SeekRightStride.into(),
+            0,
+            SeekLeftStride.into(),
+            1,
+            SeekLeftStride.into(),
+            0,
+            MoveTapeLong.into(),
+            0,
+        ]);
+        let kinds: Vec<_> = verify(&bytecode)
+            .unwrap_err()
+            .into_iter()
+            .map(|VerifyError { offset, kind }| (offset, kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (0, InvalidOpcode(0xff)),
+                (1, JumpMisaligned(2)),
+                (6, JumpOutOfBounds(108)),
+                (8, UnexpectedCommand(StartLoop)),
+                (9, ZeroStride(SeekRightStride)),
+                (13, ZeroStride(SeekLeftStride)),
+                (15, TruncatedOperand(MoveTapeLong)),
+            ]
+        );
+    }
+}